@@ -12,26 +12,145 @@ use tokenizer;
 import either::either;
 import either::right;
 import either::left;
+import result::result;
+import result::ok;
+import result::err;
 import tokenizer::token;
 import tokenizer::token_to_string;
 
 export parse;
+export continue_tokens;
+export parse_result;
+export into_result;
+export into_result_strict;
+export parse_with;
+export parse_spans_with;
+export parse_options;
+export default_options;
+export feature;
+export command_line;
+export command;
+export default_command;
+export literal;
+export referenced_files;
+export program_names;
+export referenced_variables;
+export fd_op;
+export normalize_redirections;
+export lint_warning;
+export lint;
+export connector;
+export step;
+export and_or_steps;
+export node_count;
+export depth;
+export tee_output;
+export with_args;
+export map_commands;
+export map_args;
+export fold_command_line;
+export explain;
+export command_line_to_string;
+export command_line_to_json;
+export schedule_node;
+export dependency_graph;
+export statement_stream;
+export new_statement_stream;
+export feed_statements;
+export finish_statements;
 
 enum output_sink {
     stdout,
     stderr,
-    outfile(str),
+    outfile(token),
+    appendfile(token),
+    teed(@output_sink, str),  // also appended to a log file
 }
 
 enum input_source {
     stdin,
-    infile(str),
+    infile(token),
+    here_doc_input(tokenizer::here_document),
+    herestring(token),
 }
 
-type command = {args: [str],
+/*
+Enum: fd_target
+
+Where a descriptor other than 0, 1 or 2 is redirected.
+*/
+enum fd_target {
+    fd_infile(token),
+    fd_outfile(token),
+    fd_here_doc_input(tokenizer::here_document),
+    fd_herestring(token),
+    fd_dup(uint),
+}
+
+type fd_redirect = {fd: uint, target: fd_target};
+
+/*
+Type: command
+
+A simple command and its redirections.
+
+Fields:
+
+args - The command's words, as tokenized. Quoting and expansions are
+       kept, so '$HOME' and $HOME stay different words.
+input - Where descriptor 0 reads from.
+output - Where descriptor 1 writes to.
+error - Where descriptor 2 writes to.
+fds - Redirections of any other descriptors, in the order written, and
+      any copy of descriptor 1 or 2 from a descriptor other than those
+      two, as in >&3. Such a copy leaves output or error at its default.
+closed - The descriptors closed with n>&-, in the order written. A
+         closed descriptor is not redirected as well, nor copied after
+         it is closed.
+files - The text of every file named by a redirection, in the order
+        written, including any that a later redirection replaced.
+substitutions - The command lines inside the command's $(...), in the
+                order <referenced_variables> lists them, if parsed with
+                parse_substitutions; empty otherwise.
+*/
+type command = {args: [token],
                 input: input_source,
                 output: output_sink,
-                error: output_sink };
+                error: output_sink,
+                fds: [fd_redirect],
+                closed: [uint],
+                files: [str],
+                substitutions: [command_line]};
+
+/*
+Function: default_command
+
+Build a command with no redirections.
+
+Parameters:
+
+args - The command's arguments, each a literal word.
+
+Returns:
+
+A command reading stdin and writing stdout and stderr. Use record update
+syntax to change individual sinks, e.g.
+{output: outfile(literal("log")) with default_command(["ls"])}.
+*/
+fn default_command(args: [str]) -> command {
+    ret {args: vec::map(args, literal), input: stdin, output: stdout,
+         error: stderr, fds: [], closed: [], files: [],
+         substitutions: []};
+}
+
+/*
+Function: literal
+
+A word with no quoting or expansion, e.g. for building commands by hand.
+*/
+fn literal(s: str) -> token {
+    ret tokenizer::string(s);
+}
 
 enum command_line {
     singleton(command),
@@ -40,18 +159,241 @@ enum command_line {
     background(@command_line),
     and([command_line]),
     or([command_line]),
+    negate(@command_line),
 }
 
 enum parse_result {
     parsed(command_line),
-    continuation_required,
-    error(str),
+    continuation_required(str),  // what is still open, as a prompt hint
+    error(str, option<uint>),  // with the tokenizer's offset, if it had one
+    empty,
+}
+
+/*
+Enum: feature
+
+A construct that an embedder can decline to support.
+*/
+enum feature {
+    pipelines,
+    and_or_lists,
+    background_jobs,
+    subshells,
+    redirections,
+    negation,
+    variable_expansions,
+    parameter_expansions,
+    command_substitutions,
+    arithmetic_expansions,
+    tilde_expansions,
+    globs,
+    brace_expansions,
+}
+
+/*
+Type: parse_options
+
+Options controlling <parse_with>.
+
+Fields:
+
+strict_supported - If some, only the listed features may appear in the
+                   input; any other recognized construct is rejected
+                   with a "not yet supported" error rather than being
+                   parsed. An expansion counts wherever it appears,
+                   in a word or a redirection target. If none,
+                   everything is accepted.
+max_tokens - If some, the most tokens the input may contain.
+max_nesting - If some, the deepest the input may nest subshells and,
+              with parse_substitutions, command substitutions.
+max_pipeline_width - If some, the most stages any pipeline may have.
+max_here_doc_bytes - If some, the most bytes any here-doc body may hold.
+parse_substitutions - Whether to parse the command line inside each
+                      $(...) into the command's substitutions. Each one
+                      counts as a level of nesting.
+*/
+type parse_options = {strict_supported: option<[feature]>,
+                      max_tokens: option<uint>,
+                      max_nesting: option<uint>,
+                      max_pipeline_width: option<uint>,
+                      max_here_doc_bytes: option<uint>,
+                      parse_substitutions: bool};
+
+/*
+Function: default_options
+
+Returns:
+
+The parse_options used by <parse>, which accept every construct, impose
+no limits and leave $(...) unparsed.
+*/
+fn default_options() -> parse_options {
+    ret {strict_supported: none,
+         max_tokens: none,
+         max_nesting: none,
+         max_pipeline_width: none,
+         max_here_doc_bytes: none,
+         parse_substitutions: false};
+}
+
+fn over_limit(limit: option<uint>, n: uint) -> bool {
+    ret alt limit {
+      some(max) { n > max }
+      none { false }
+    };
+}
+
+fn limit_error(what: str, limit: option<uint>) -> parse_result {
+    ret error(what + " (limit " + uint::to_str(option::get(limit), 10u)
+              + ").", none);
+}
+
+fn largest_here_doc(tokens: [token]) -> uint {
+    let largest = 0u;
+    for t in tokens {
+        alt t {
+          tokenizer::here_doc(h) | tokenizer::fd_here_doc(_, h) {
+            largest = uint::max(largest, str::len(h.body));
+          }
+          _ { }
+        }
+    }
+    ret largest;
+}
+
+fn widest_pipeline_in(cls: [command_line]) -> uint {
+    let widest = 0u;
+    for cl in cls {
+        widest = uint::max(widest, widest_pipeline(cl));
+    }
+    ret widest;
+}
+
+fn widest_pipeline(cl: command_line) -> uint {
+    ret alt cl {
+      singleton(_) { 1u }
+      pipeline(cls) { uint::max(vec::len(cls), widest_pipeline_in(cls)) }
+      sequence(cls) { widest_pipeline_in(cls) }
+      background(bg) { widest_pipeline(*bg) }
+      and(cls) { widest_pipeline_in(cls) }
+      or(cls) { widest_pipeline_in(cls) }
+      negate(cl) { widest_pipeline(*cl) }
+    };
+}
+
+fn token_feature(t: token) -> option<feature> {
+    ret alt t {
+      tokenizer::pipe | tokenizer::pipe_both { some(pipelines) }
+      tokenizer::and | tokenizer::or { some(and_or_lists) }
+      tokenizer::background { some(background_jobs) }
+      tokenizer::negate { some(negation) }
+      tokenizer::open_subshell | tokenizer::close_subshell {
+        some(subshells)
+      }
+      tokenizer::redirect_output(_)
+      | tokenizer::redirect_output_append(_)
+      | tokenizer::redirect_error(_)
+      | tokenizer::redirect_error_append(_)
+      | tokenizer::redirect_error_to_output
+      | tokenizer::redirect_both(_)
+      | tokenizer::redirect_both_append(_)
+      | tokenizer::redirect_input(_)
+      | tokenizer::here_doc(_)
+      | tokenizer::here_string(_)
+      | tokenizer::redirect_fd(_, _)
+      | tokenizer::redirect_fd_input(_, _)
+      | tokenizer::fd_here_doc(_, _)
+      | tokenizer::fd_here_string(_, _)
+      | tokenizer::dup_fd(_, _)
+      | tokenizer::close_fd(_) { some(redirections) }
+      tokenizer::variable(_) { some(variable_expansions) }
+      tokenizer::parameter(_, _, _) { some(parameter_expansions) }
+      tokenizer::command_subst(_) { some(command_substitutions) }
+      tokenizer::arith(_) { some(arithmetic_expansions) }
+      tokenizer::tilde(_, _) { some(tilde_expansions) }
+      tokenizer::glob(_) { some(globs) }
+      tokenizer::brace_expand(_, _, _) { some(brace_expansions) }
+      _ { none }
+    };
+}
+
+// The features t uses: its own, then those of the words inside it, as
+// the pieces of a word or the target of a redirection.
+fn token_features(t: token) -> [feature] {
+    let fs = alt token_feature(t) {
+      some(f) { [f] }
+      none { [] }
+    };
+    let inner = alt t {
+      tokenizer::word(pieces) { pieces }
+      tokenizer::parameter(_, _, w) { [*w] }
+      tokenizer::brace_expand(pre, body, post) {
+        alt body {
+          // The alternatives are kept as written.
+          tokenizer::brace_list(alts) {
+            let ts = [*pre];
+            for a in alts {
+                ts += tokenizer::tokenize(a);
+            }
+            ts + [*post]
+          }
+          tokenizer::brace_range(_, _) { [*pre, *post] }
+        }
+      }
+      tokenizer::redirect_output(w) | tokenizer::redirect_output_append(w)
+      | tokenizer::redirect_error(w) | tokenizer::redirect_error_append(w)
+      | tokenizer::redirect_both(w) | tokenizer::redirect_both_append(w)
+      | tokenizer::redirect_input(w) | tokenizer::here_string(w) { [*w] }
+      tokenizer::redirect_fd(_, w) | tokenizer::redirect_fd_input(_, w)
+      | tokenizer::fd_here_string(_, w) { [*w] }
+      _ { [] }
+    };
+    for w in inner {
+        fs += token_features(w);
+    }
+    ret fs;
+}
+
+fn feature_name(f: feature) -> str {
+    ret alt f {
+      pipelines { "Pipelines" }
+      and_or_lists { "And/or lists" }
+      background_jobs { "Background jobs" }
+      subshells { "Subshells" }
+      redirections { "Redirections" }
+      negation { "Negated pipelines" }
+      variable_expansions { "Variable expansions" }
+      parameter_expansions { "Parameter expansions" }
+      command_substitutions { "Command substitutions" }
+      arithmetic_expansions { "Arithmetic expansions" }
+      tilde_expansions { "Tilde expansions" }
+      globs { "Globs" }
+      brace_expansions { "Brace expansions" }
+    };
+}
+
+// The message for the first unsupported token, and that token's index.
+fn check_supported(tokens: [token], supported: [feature])
+    -> option<(str, uint)> {
+    let i = 0u;
+    while i < vec::len(tokens) {
+        for f in token_features(tokens[i]) {
+            if !vec::contains(supported, f) {
+                ret some((feature_name(f) + " are not yet supported.", i));
+            }
+        }
+        i += 1u;
+    }
+    ret none;
 }
 
 /*
 Function: parse
 
-Parse a sequence of tokens into a command line.
+Parse a sequence of tokens into a command line. As in sh, pipes bind
+tighter than && and ||, which bind tighter than ; and &, and a leading !
+negates the pipeline it starts. A trailing ; still makes a sequence, of
+one statement, but nothing may stand empty between two separators.
 
 Parameters:
 
@@ -59,167 +401,657 @@ tokens - A vector of tokens from a command line.
 
 Returns:
 
-A parse_result. If continuation_required is returned, the caller must
-harvest another commandline from the user, and call parse again with
-the new tokens concatenated to the present tokens.
+A parse_result. If the tokens end in an explicit continuation or in a
+pipe, continuation_required is returned, and the caller must harvest
+another commandline from the user, and call parse again with the new
+tokens joined to the present tokens by <continue_tokens>. Its reason is
+"pipe>" after a | or |&, "heredoc>" in a here-doc body, "arith>" in an
+unfinished $((, and empty after a backslash. A trailing && or
+||, unlike a pipe, is an error rather than a request for more input. If
+there are no tokens at all, the result is empty, and there is nothing to
+run. An error token stops the parse with its message and offset; errors the
+parser finds itself have no offset.
 */
 fn parse(tokens: [token]) -> parse_result {
+    ret parse_with(tokens, default_options());
+}
+
+/*
+Function: parse_with
+
+Parse a sequence of tokens into a command line, as <parse> does, subject
+to the given options.
+
+Parameters:
+
+tokens - A vector of tokens from a command line.
+opts - The parse_options to apply.
+
+Returns:
+
+A parse_result, as for <parse>. Constructs outside opts.strict_supported
+produce an error naming the construct, and input over one of the limits
+produces an error naming the limit. Having no spans, these errors have
+no offset; see <parse_spans_with>.
+*/
+fn parse_with(tokens: [token], opts: parse_options) -> parse_result {
+    ret parse_checked(tokens, [], opts, 0u);
+}
+
+/*
+Function: parse_spans_with
+
+Parse tokens with their spans, as from tokenizer::tokenize_spans, as
+<parse_with> does.
+
+Parameters:
+
+spanned - A vector of tokens and their spans from a command line.
+opts - The parse_options to apply.
+
+Returns:
+
+A parse_result, as for <parse_with>, except that an error for a
+construct outside opts.strict_supported carries the character offset
+where the construct starts.
+*/
+fn parse_spans_with(spanned: [(token, tokenizer::span)], opts: parse_options)
+    -> parse_result {
+    let tokens: [token] = [];
+    let starts: [uint] = [];
+    for pair in spanned {
+        let (t, sp) = pair;
+        tokens += [t];
+        starts += [sp.start];
+    }
+    ret parse_checked(tokens, starts, opts, 0u);
+}
+
+// starts holds the offset of each token, or is empty if none are known.
+// depth is how deeply the tokens are nested already, as inside $(...).
+fn parse_checked(tokens: [token], starts: [uint], opts: parse_options,
+                 depth: uint) -> parse_result {
+    if over_limit(opts.max_tokens, vec::len(tokens)) {
+        ret limit_error("Too many tokens", opts.max_tokens);
+    }
+    if over_limit(opts.max_here_doc_bytes, largest_here_doc(tokens)) {
+        ret limit_error("Here-doc body too large", opts.max_here_doc_bytes);
+    }
+    alt opts.strict_supported {
+      some(supported) {
+        alt check_supported(tokens, supported) {
+          some((e, i)) {
+            ret error(e, if i < vec::len(starts) { some(starts[i]) }
+                         else { none });
+          }
+          none { }
+        }
+      }
+      none { }
+    }
     if vec::is_empty(tokens) {
-        ret parsed(sequence([]));
-    } else if vec::last(tokens) == tokenizer::continuation {
-        ret continuation_required;
+        ret empty;
     }
-    let idx = 0u;
-    ret parse_tokens(tokens, 0u, idx);
+    alt vec::last(tokens) {
+      tokenizer::continuation(r) { ret continuation_required(r); }
+      tokenizer::pipe | tokenizer::pipe_both {
+        ret continuation_required("pipe>");
+      }
+      _ { }
+    }
+    let cur = new_cursor(tokens);
+    let result = parse_tokens(cur, opts, depth, 0u);
+    alt result {
+      parsed(cl) {
+        if over_limit(opts.max_pipeline_width, widest_pipeline(cl)) {
+            ret limit_error("Pipeline has too many stages",
+                            opts.max_pipeline_width);
+        }
+      }
+      _ { }
+    }
+    ret result;
+}
+
+/*
+Function: continue_tokens
+
+Join the tokens of a continuation line to the tokens that asked for it.
+
+Parameters:
+
+pending - The tokens for which <parse> returned continuation_required.
+more - The tokens of the next line.
+
+Returns:
+
+The combined tokens, without the continuation marker that ended pending,
+so that an empty continuation line completes the command rather than
+asking for yet another line. A here-doc body or $(( left open is part of
+a token, so joining tokens cannot finish it; tokenize the joined text
+again instead, as a statement_stream does.
+*/
+fn continue_tokens(pending: [token], more: [token]) -> [token] {
+    if vec::is_not_empty(pending) {
+        alt vec::last(pending) {
+          tokenizer::continuation(_) {
+            ret vec::slice(pending, 0u, vec::len(pending) - 1u) + more;
+          }
+          _ { }
+        }
+    }
+    ret pending + more;
+}
+
+/*
+Function: into_result
+
+Convert a parse_result for callers that cannot supply more input.
+
+Parameters:
+
+pr - The result of <parse>.
+
+Returns:
+
+ok with the command line if one was parsed, or with an empty sequence
+for empty input; err with the message otherwise, including when more
+input was required.
+*/
+fn into_result(pr: parse_result) -> result<command_line, str> {
+    ret alt pr {
+      parsed(cl) { ok(cl) }
+      empty { ok(sequence([])) }
+      continuation_required(_) { err("Continuation required.") }
+      error(e, _) { err(e) }
+    };
+}
+
+/*
+Function: into_result_strict
+
+Convert a parse_result for reading a complete script.
+
+Parameters:
+
+pr - The result of <parse>.
+
+Returns:
+
+ok with the command line if one was parsed; err otherwise. Input that
+asked for a continuation ended too soon, and is reported as an
+unexpected end of input, and empty input is an error.
+*/
+fn into_result_strict(pr: parse_result) -> result<command_line, str> {
+    ret alt pr {
+      parsed(cl) { ok(cl) }
+      empty { err("Empty command line.") }
+      continuation_required(_) { err("Unexpected end of input.") }
+      error(e, _) { err(e) }
+    };
+}
+
+fn redirects_fd(fds: [fd_redirect], fd: uint) -> bool {
+    for r in fds {
+        if r.fd == fd { ret true; }
+    }
+    ret false;
+}
+
+fn without_fd(fds: [fd_redirect], fd: uint) -> [fd_redirect] {
+    let kept: [fd_redirect] = [];
+    for r in fds {
+        if r.fd != fd { kept += [r]; }
+    }
+    ret kept;
+}
+
+// The descriptor that fd is copied from, if fds holds such a copy.
+fn dup_target(fds: [fd_redirect], fd: uint) -> option<uint> {
+    for r in fds {
+        if r.fd == fd {
+            alt r.target {
+              fd_dup(n) { ret some(n); }
+              _ { }
+            }
+        }
+    }
+    ret none;
+}
+
+fn error_redirected(e: output_sink, fds: [fd_redirect]) -> bool {
+    ret e != stderr || redirects_fd(fds, 2u);
+}
+
+fn multiple_fd_error(fd: uint) -> str {
+    ret "Multiple redirects of descriptor " + uint::to_str(fd, 10u) + ".";
+}
+
+// Whether a descriptor above 2 is a copy of fd. The command keeps no
+// order between such copies and the sinks of 1 and 2, so fd may not be
+// redirected afterwards.
+fn copied_from(fds: [fd_redirect], fd: uint) -> bool {
+    for r in fds {
+        if r.fd > 2u && r.target == fd_dup(fd) { ret true; }
+    }
+    ret false;
+}
+
+fn closed_error(fd: uint) -> str {
+    ret "Descriptor " + uint::to_str(fd, 10u) + " is closed.";
+}
+
+fn copied_error(fd: uint) -> str {
+    ret "Descriptor " + uint::to_str(fd, 10u)
+        + " is redirected after being copied.";
 }
 
 fn make_command(tokens: [token]) -> either<command, str> {
     assert vec::is_not_empty(tokens);
-    let args: [str] = [];
+    let args: [token] = [];
     let i = stdin;
     let o = stdout;
     let e = stderr;
+    let fds: [fd_redirect] = [];
+    let closed: [uint] = [];
+    let files: [str] = [];
     for t in tokens {
         alt t {
-          tokenizer::string(s) { args += [s]; }
-          tokenizer::redirect_output(s) {
-            if (o != stdout) { ret right("Multiple output redirects."); }
-            o = outfile(s);
+          // Nothing is expanded yet, so words, here and in redirection
+          // targets, are kept as the tokenizer left them.
+          tokenizer::string(_) | tokenizer::variable(_)
+          | tokenizer::parameter(_, _, _) | tokenizer::command_subst(_)
+          | tokenizer::arith(_) | tokenizer::tilde(_, _) | tokenizer::glob(_)
+          | tokenizer::brace_expand(_, _, _) | tokenizer::word(_) {
+            args += [t];
+          }
+          // As in sh, the last output redirect wins, target and mode alike.
+          tokenizer::redirect_output(w) {
+            if copied_from(fds, 1u) { ret right(copied_error(1u)); }
+            o = outfile(*w);
+            fds = without_fd(fds, 1u);
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::redirect_output_append(w) {
+            if copied_from(fds, 1u) { ret right(copied_error(1u)); }
+            o = appendfile(*w);
+            fds = without_fd(fds, 1u);
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::redirect_error(w) {
+            if error_redirected(e, fds) {
+                ret right("Multiple error redirects.");
+            }
+            if copied_from(fds, 2u) { ret right(copied_error(2u)); }
+            e = outfile(*w);
+            files += [tokenizer::word_text(*w)];
           }
-          tokenizer::redirect_error(s) {
-            if (e != stderr) { ret right("Multiple error redirects."); }
-            e = outfile(s);
+          tokenizer::redirect_error_append(w) {
+            if error_redirected(e, fds) {
+                ret right("Multiple error redirects.");
+            }
+            if copied_from(fds, 2u) { ret right(copied_error(2u)); }
+            e = appendfile(*w);
+            files += [tokenizer::word_text(*w)];
           }
           tokenizer::redirect_error_to_output {
-            if (e != stderr) { ret right("Multiple error redirects."); }
+            if error_redirected(e, fds) {
+                ret right("Multiple error redirects.");
+            }
+            if copied_from(fds, 2u) { ret right(copied_error(2u)); }
+            if vec::contains(closed, 1u) { ret right(closed_error(1u)); }
+            alt dup_target(fds, 1u) {
+              some(n) { fds += [{fd: 2u, target: fd_dup(n)}]; }
+              none { e = o; }
+            }
+          }
+          tokenizer::redirect_both(w) {
+            if error_redirected(e, fds) {
+                ret right("Multiple error redirects.");
+            }
+            if copied_from(fds, 2u) { ret right(copied_error(2u)); }
+            if copied_from(fds, 1u) { ret right(copied_error(1u)); }
+            o = outfile(*w);
+            e = o;
+            fds = without_fd(fds, 1u);
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::redirect_both_append(w) {
+            if error_redirected(e, fds) {
+                ret right("Multiple error redirects.");
+            }
+            if copied_from(fds, 2u) { ret right(copied_error(2u)); }
+            if copied_from(fds, 1u) { ret right(copied_error(1u)); }
+            o = appendfile(*w);
             e = o;
+            fds = without_fd(fds, 1u);
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::redirect_input(w) {
+            if (i != stdin) { ret right("Multiple input redirects."); }
+            i = infile(*w);
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::here_doc(h) {
+            if (i != stdin) { ret right("Multiple input redirects."); }
+            i = here_doc_input(h);
           }
-          tokenizer::redirect_input(s) {
+          tokenizer::here_string(w) {
             if (i != stdin) { ret right("Multiple input redirects."); }
-            i = infile(s);
+            i = herestring(*w);
+          }
+          tokenizer::redirect_fd(fd, w) {
+            if redirects_fd(fds, fd) { ret right(multiple_fd_error(fd)); }
+            fds += [{fd: fd, target: fd_outfile(*w)}];
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::redirect_fd_input(fd, w) {
+            if redirects_fd(fds, fd) { ret right(multiple_fd_error(fd)); }
+            fds += [{fd: fd, target: fd_infile(*w)}];
+            files += [tokenizer::word_text(*w)];
+          }
+          tokenizer::fd_here_doc(fd, h) {
+            if redirects_fd(fds, fd) { ret right(multiple_fd_error(fd)); }
+            fds += [{fd: fd, target: fd_here_doc_input(h)}];
+          }
+          tokenizer::fd_here_string(fd, w) {
+            if redirects_fd(fds, fd) { ret right(multiple_fd_error(fd)); }
+            fds += [{fd: fd, target: fd_herestring(*w)}];
+          }
+          // Descriptors 1 and 2 copied from one that has no sink of its own,
+          // as in >&3, are recorded with the other descriptors instead.
+          // A copy from a descriptor closed earlier is refused, so that
+          // performing every close last keeps the written meaning.
+          tokenizer::dup_fd(fd, target) {
+            if vec::contains(closed, target) {
+                ret right(closed_error(target));
+            }
+            if fd == target {
+            } else if fd == 1u {
+                if copied_from(fds, 1u) { ret right(copied_error(1u)); }
+                let from = if target == 2u { dup_target(fds, 2u) }
+                           else { some(target) };
+                fds = without_fd(fds, 1u);
+                alt from {
+                  some(n) {
+                    o = stdout;
+                    fds += [{fd: 1u, target: fd_dup(n)}];
+                  }
+                  none { o = e; }
+                }
+            } else if fd == 2u {
+                if error_redirected(e, fds) {
+                    ret right("Multiple error redirects.");
+                }
+                if copied_from(fds, 2u) { ret right(copied_error(2u)); }
+                let from = if target == 1u { dup_target(fds, 1u) }
+                           else { some(target) };
+                alt from {
+                  some(n) { fds += [{fd: 2u, target: fd_dup(n)}]; }
+                  none { e = o; }
+                }
+            } else if fd > 2u {
+                if redirects_fd(fds, fd) { ret right(multiple_fd_error(fd)); }
+                fds += [{fd: fd, target: fd_dup(target)}];
+            } else {
+                ret right("Cannot duplicate descriptor "
+                          + uint::to_str(target, 10u) + " onto descriptor "
+                          + uint::to_str(fd, 10u) + ".");
+            }
+          }
+          tokenizer::close_fd(fd) {
+            if vec::contains(closed, fd) {
+                ret right("Descriptor " + uint::to_str(fd, 10u)
+                          + " is closed more than once.");
+            }
+            closed += [fd];
           }
           _ { ret right("Unexpected token: " + token_to_string(t)); }
         }
     }
-    ret left({args: args, input: i, output: o, error: e});
+    for fd in closed {
+        let redirected = alt fd {
+          0u { i != stdin }
+          1u { o != stdout || redirects_fd(fds, 1u) }
+          2u { e != stderr || redirects_fd(fds, 2u) }
+          _ { redirects_fd(fds, fd) }
+        };
+        if redirected {
+            ret right("Descriptor " + uint::to_str(fd, 10u)
+                      + " is both closed and redirected.");
+        }
+    }
+    ret left({args: args, input: i, output: o, error: e, fds: fds,
+              closed: closed, files: files, substitutions: []});
 }
 
 enum part_parse {
     cmd(command),
     subshell(command_line),
     sep(token),
+    neg,
+}
+
+// A subshell has no node of its own to redirect, so "(...) |&" points
+// whatever reaches the subshell's standard error at its standard output in
+// each command inside. A stage of a pipeline inside then sends its errors
+// down that pipeline rather than the outer one.
+fn stderr_to_stdout(s: output_sink) -> output_sink {
+    ret alt s {
+      stderr { stdout }
+      teed(inner, f) { teed(@stderr_to_stdout(*inner), f) }
+      _ { s }
+    };
+}
+
+fn pipe_subshell_errors(c: command) -> command {
+    let fds = vec::map(c.fds) {|r|
+        alt r.target {
+          fd_dup(2u) { {fd: r.fd, target: fd_dup(1u)} }
+          _ { r }
+        }
+    };
+    ret {output: stderr_to_stdout(c.output),
+         error: stderr_to_stdout(c.error),
+         fds: fds with c};
 }
 
 fn part_to_cl(p: part_parse) -> command_line {
     ret alt p {
       cmd(c) { singleton(c) }
       subshell(cl) { cl }
-      sep(_) { fail("part_to_cl doesn't convert separators."); }
+      sep(_) | neg { fail("part_to_cl only converts commands."); }
+    };
+}
+
+// A single pipeline stage. `after_op` is true when the stage follows an
+// operator and so must be a real command rather than a bare redirection.
+fn parse_stage(parts: [part_parse], missing: str, after_op: bool,
+               &idx: uint) -> either<command_line, str> {
+    if idx == vec::len(parts) {
+        ret right(missing);
+    }
+    let p = parts[idx];
+    alt p {
+      sep(_) {
+        ret right("Found a separator where a command was expected.");
+      }
+      neg { ret right("Unexpected '!'."); }
+      cmd(c) {
+        if after_op && vec::is_empty(c.args) {
+            ret right("Found a redirection where a command was expected.");
+        }
+      }
+      subshell(_) { }
+    }
+    idx += 1u;
+    if idx < vec::len(parts) {
+        alt parts[idx] {
+          sep(_) { }
+          _ { ret right("Found a command where a separator was expected."); }
+        }
+    }
+    ret left(part_to_cl(p));
+}
+
+// An optionally negated pipeline.
+fn parse_pipeline(parts: [part_parse], missing: str, after_op: bool,
+                  &idx: uint) -> either<command_line, str> {
+    if idx < vec::len(parts) && parts[idx] == neg {
+        idx += 1u;
+        ret alt parse_pipeline(parts, missing, true, idx) {
+          left(cl) { left(negate(@cl)) }
+          right(e) { right(e) }
+        };
+    }
+    let cl = alt parse_stage(parts, missing, after_op, idx) {
+      left(cl) { cl }
+      right(e) { ret right(e); }
     };
+    while idx < vec::len(parts) && parts[idx] == sep(tokenizer::pipe) {
+        idx += 1u;
+        let rhs = alt parse_stage(parts, missing, true, idx) {
+          left(cl) { cl }
+          right(e) { ret right(e); }
+        };
+        cl = alt cl {
+          pipeline(cls) { pipeline(cls + [rhs]) }
+          _ { pipeline([cl, rhs]) }
+        };
+    }
+    ret left(cl);
 }
 
-fn append_to_cl(&cl: command_line, p: part_parse) {
-    cl = alt cl {
-      singleton(_) { fail("cl may not be singleton."); }
-      pipeline(args) { pipeline(args + [part_to_cl(p)]) }
-      sequence(args) { sequence(args + [part_to_cl(p)]) }
-      background(_) { sequence([cl, part_to_cl(p)]) }
-      and(args) { and(args + [part_to_cl(p)]) }
-      or(args) { or(args + [part_to_cl(p)]) }
+// Pipelines joined by && and ||, which bind equally tightly and group
+// from the left.
+fn parse_and_or(parts: [part_parse], missing: str, &idx: uint)
+    -> either<command_line, str> {
+    let cl = alt parse_pipeline(parts, missing, false, idx) {
+      left(cl) { cl }
+      right(e) { ret right(e); }
     };
+    while idx < vec::len(parts) {
+        let is_and = alt parts[idx] {
+          sep(tokenizer::and) { true }
+          sep(tokenizer::or) { false }
+          _ { break; }
+        };
+        idx += 1u;
+        let rhs = alt parse_pipeline(parts, missing, true, idx) {
+          left(cl) { cl }
+          right(e) { ret right(e); }
+        };
+        cl = alt cl {
+          and(cls) if is_and { and(cls + [rhs]) }
+          or(cls) if !is_and { or(cls + [rhs]) }
+          _ if is_and { and([cl, rhs]) }
+          _ { or([cl, rhs]) }
+        };
+    }
+    ret left(cl);
 }
 
-fn finish_parse(parts: [part_parse]) -> parse_result {
-    assert vec::is_not_empty(parts);
-    let cur_cl = alt parts[0u] {
-      sep(_)  { ret error("No initial command."); }
-      _ { part_to_cl(parts[0u]) }
+fn finish_parse(parts: [part_parse], level: uint) -> parse_result {
+    let missing = if level > 0u {
+        "Missing command before ')'."
+    } else {
+        "Missing command at end of line."
     };
-    if vec::len(parts) == 1u {
-        ret parsed(cur_cl);
+    if vec::is_empty(parts) {
+        ret error(missing, none);
+    }
+    alt parts[0u] {
+      sep(_) { ret error("No initial command.", none); }
+      _ { }
     }
-    let idx = 1u;
-    let cmd_required = false;
-    let cmd_allowed = false;
+    let items: [command_line] = [];
+    let separated = false;
+    let idx = 0u;
     while idx < vec::len(parts) {
+        let item = alt parse_and_or(parts, missing, idx) {
+          left(cl) { cl }
+          right(e) { ret error(e, none); }
+        };
+        if idx == vec::len(parts) {
+            items += [item];
+            break;
+        }
         alt parts[idx] {
-          cmd(_)
-          | subshell(_) {
-            if !cmd_allowed {
-                ret error("Found a command where a separator was expected.");
-            }
-            cmd_required = false;
-            cmd_allowed = false;
-            alt cur_cl {
-              _ { append_to_cl(cur_cl, parts[idx]); }
-            }
+          sep(tokenizer::background) { items += [background(@item)]; }
+          sep(tokenizer::sequence) {
+            items += [item];
+            separated = true;
           }
-          sep(t) {
-            if cmd_required {
-                ret error("Found a separator where a command was expected.");
-            }
-            cmd_required = true;
-            cmd_allowed = true;
-            cur_cl = alt t {
-              tokenizer::pipe {
-                alt cur_cl {
-                  pipeline(_) { cur_cl }
-                  _ { pipeline([cur_cl]) }
-                }
-              }
-              tokenizer::and {
-                alt cur_cl {
-                  and(_) { cur_cl }
-                  _ { and([cur_cl]) }
-                }
-              }
-              tokenizer::or {
-                alt cur_cl {
-                  or(_) { cur_cl }
-                  _ { or([cur_cl]) }
-                }
-              }
-              tokenizer::background {
-                cmd_required = false;
-                background(@cur_cl)
-              }
-              tokenizer::sequence {
-                alt cur_cl {
-                  sequence(_) { cur_cl }
-                  _ { sequence([cur_cl]) }
-                }
-              }
-              _ {
-                fail("Unexpected partial parse.");
+          _ { fail("Unexpected partial parse."); }
+        }
+        idx += 1u;
+        if idx < vec::len(parts) {
+            alt parts[idx] {
+              sep(_) {
+                ret error("Found a separator where a command was expected.",
+                          none);
               }
+              _ { }
             }
-          }
         }
-        idx += 1u;
     }
-    ret if cmd_required {
-        error("Missing command at end of line.")
+    ret if vec::len(items) == 1u && !separated {
+        parsed(items[0u])
     } else {
-        parsed(cur_cl)
+        parsed(sequence(items))
     };
 }
 
-fn parse_tokens(tokens: [token], level: uint, &idx: uint) -> parse_result {
-    let parts: [part_parse] = [];
-    let cur: [token] = [];
+// The tokens left to parse. A subshell is parsed with its caller's
+// cursor, so that it carries on from the ( and its caller from the ).
+type token_cursor = {tokens: [token], pos: uint};
 
-    #macro([#make_command[ts, ps],
-            if vec::is_not_empty(ts) {
+fn new_cursor(tokens: [token]) -> token_cursor {
+    ret {tokens: tokens, pos: 0u};
+}
+
+fn at_end(cur: token_cursor) -> bool {
+    ret cur.pos >= vec::len(cur.tokens);
+}
+
+// The next token, without consuming it.
+fn peek(cur: token_cursor) -> option<token> {
+    ret if at_end(cur) { none } else { some(cur.tokens[cur.pos]) };
+}
+
+// Consumes the next token and returns it.
+fn next(&cur: token_cursor) -> option<token> {
+    let t = peek(cur);
+    if option::is_some(t) {
+        cur = {pos: cur.pos + 1u with cur};
+    }
+    ret t;
+}
+
+// level counts the subshells open in these tokens, and depth the nesting
+// they start at.
+fn parse_tokens(&tokens: token_cursor, opts: parse_options, depth: uint,
+                level: uint) -> parse_result {
+    let parts: [part_parse] = [];
+    let cur: [token] = [];
+
+    #macro([#make_command[ts, ps],
+            if vec::is_not_empty(ts) {
                 alt make_command(ts) {
-                  left(c) { ps += [cmd(c)]; ts = []; }
-                  right(e) { ret error(e); }
+                  left(c) {
+                    alt with_substitutions(c, opts, depth + level) {
+                      left(c) { ps += [cmd(c)]; ts = []; }
+                      right(r) { ret r; }
+                    }
+                  }
+                  right(e) { ret error(e, none); }
                 }
             }]);
 
-    while idx < vec::len(tokens) {
-        let t = tokens[idx];
+    while !at_end(tokens) {
+        let t = option::get(next(tokens));
         alt t {
-          tokenizer::error(e) { ret error(e); }
+          tokenizer::error(e, at) { ret error(e, some(at)); }
           tokenizer::pipe
           | tokenizer::and
           | tokenizer::or
@@ -228,65 +1060,1485 @@ fn parse_tokens(tokens: [token], level: uint, &idx: uint) -> parse_result {
             #make_command[cur, parts];
             parts += [sep(t)];
           }
+          tokenizer::pipe_both {
+            // Short for "2>&1 |", applied after the command's own
+            // redirections.
+            #make_command[cur, parts];
+            if vec::is_not_empty(parts) {
+                let n = vec::len(parts);
+                alt parts[n - 1u] {
+                  cmd(c) {
+                    // Descriptor 1 may itself be a copy, as in >&3.
+                    let fds = without_fd(c.fds, 2u);
+                    let piped = alt dup_target(c.fds, 1u) {
+                      some(fd) {
+                        {error: stderr,
+                         fds: fds + [{fd: 2u, target: fd_dup(fd)}] with c}
+                      }
+                      none { {error: c.output, fds: fds with c} }
+                    };
+                    parts = vec::slice(parts, 0u, n - 1u) + [cmd(piped)];
+                  }
+                  subshell(cl) {
+                    parts = vec::slice(parts, 0u, n - 1u)
+                        + [subshell(map_commands(cl, pipe_subshell_errors))];
+                  }
+                  sep(_) | neg { }
+                }
+            }
+            parts += [sep(tokenizer::pipe)];
+          }
+          tokenizer::negate {
+            #make_command[cur, parts];
+            parts += [neg];
+          }
           tokenizer::open_subshell {
+            if over_limit(opts.max_nesting, depth + level + 1u) {
+                ret limit_error("Subshells nested too deeply",
+                                opts.max_nesting);
+            }
             #make_command[cur, parts];
-            idx += 1u;
-            alt parse_tokens(tokens, level + 1u, idx) {
+            alt parse_tokens(tokens, opts, depth, level + 1u) {
               parsed(cl) { parts += [subshell(cl)]; }
-              error(e) { ret error(e); }
-              continuation_required { fail("Inconceivable!"); }
+              error(e, at) { ret error(e, at); }
+              continuation_required(_) | empty { fail("Inconceivable!"); }
             }
           }
           tokenizer::close_subshell {
             if level == 0u {
-                ret error("Unexpected ')'.");
+                ret error("Unexpected ')'.", none);
             }
             #make_command[cur, parts];
-            ret finish_parse(parts);
+            ret finish_parse(parts, level);
           }
-          tokenizer::continuation {  /* ignore me! */ }
+          tokenizer::continuation(_) {  /* ignore me! */ }
           _ { cur += [t]; }
         }
-        idx += 1u;
     }
     if level > 0u {
-        ret error("Expected ')'");
+        ret error("Expected ')'", none);
     }
     #make_command[cur, parts];
-    ret finish_parse(parts);
+    ret finish_parse(parts, level);
+}
+
+fn sink_file(s: output_sink) -> option<str> {
+    ret alt s {
+      outfile(f) { some(tokenizer::word_text(f)) }
+      appendfile(f) { some(tokenizer::word_text(f)) }
+      teed(s, _) { sink_file(*s) }
+      _ { none }
+    };
+}
+
+/*
+Function: referenced_files
+
+Collect the files a command line redirects from or to.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+Every file a redirection names, in the order the redirections were
+written, and once for each time it is named. An output file replaced by
+a later output redirect is still listed, and 2>&1 names no file of its
+own. Here-doc delimiters are not file names and are never included.
+*/
+fn referenced_files(cl: command_line) -> [str] {
+    ret fold_command_line(cl, []) {|files, c| files + c.files };
+}
+
+/*
+Function: program_names
+
+Collect the programs a command line runs, e.g. for a security prompt.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+The first word of every command, subshells included, in the order the
+commands appear. A command that is only redirections runs nothing and is
+skipped. Duplicates are kept.
+*/
+fn program_names(cl: command_line) -> [str] {
+    ret fold_command_line(cl, []) {|names, c|
+        if vec::is_empty(c.args) { names } else {
+            names + [tokenizer::word_text(c.args[0u])]
+        }
+    };
+}
+
+// The variables, parameters and command substitutions a token expands,
+// in order, including those in its redirection target and in a
+// parameter's word but not those inside a substitution.
+fn expansions(t: token) -> [token] {
+    ret alt t {
+      tokenizer::variable(_) | tokenizer::command_subst(_) { [t] }
+      tokenizer::parameter(_, _, w) { [t] + expansions(*w) }
+      tokenizer::brace_expand(pre, body, post) {
+        let es = expansions(*pre);
+        alt body {
+          // The alternatives are kept as written.
+          tokenizer::brace_list(alts) {
+            for a in alts {
+                es += tokens_expansions(tokenizer::tokenize(a));
+            }
+          }
+          tokenizer::brace_range(_, _) { }
+        }
+        es + expansions(*post)
+      }
+      tokenizer::word(pieces) { tokens_expansions(pieces) }
+      tokenizer::redirect_output(w) | tokenizer::redirect_output_append(w)
+      | tokenizer::redirect_error(w) | tokenizer::redirect_error_append(w)
+      | tokenizer::redirect_both(w) | tokenizer::redirect_both_append(w)
+      | tokenizer::redirect_input(w) | tokenizer::here_string(w)
+      | tokenizer::redirect_fd(_, w) | tokenizer::redirect_fd_input(_, w)
+      | tokenizer::fd_here_string(_, w) {
+        expansions(*w)
+      }
+      _ { [] }
+    };
+}
+
+fn tokens_expansions(ts: [token]) -> [token] {
+    let es: [token] = [];
+    for t in ts {
+        es += expansions(t);
+    }
+    ret es;
+}
+
+fn tokens_variables(ts: [token]) -> [str] {
+    let names: [str] = [];
+    for e in tokens_expansions(ts) {
+        alt e {
+          tokenizer::variable(n) | tokenizer::parameter(n, _, _) {
+            names += [n];
+          }
+          tokenizer::command_subst(s) {
+            names += tokens_variables(tokenizer::tokenize(s));
+          }
+          _ { }
+        }
+    }
+    ret names;
+}
+
+// The text inside each $(...) of a command, in the order
+// <referenced_variables> searches it.
+fn substitution_texts(c: command) -> [str] {
+    let texts: [str] = [];
+    for e in tokens_expansions(command_tokens(c)) {
+        alt e {
+          tokenizer::command_subst(s) { texts += [s]; }
+          _ { }
+        }
+    }
+    ret texts;
+}
+
+// With opts.parse_substitutions, c with the command lines inside its
+// $(...) parsed. Each is nested one level below c, which is at depth.
+fn with_substitutions(c: command, opts: parse_options, depth: uint)
+    -> either<command, parse_result> {
+    if !opts.parse_substitutions {
+        ret left(c);
+    }
+    let texts = substitution_texts(c);
+    if vec::is_empty(texts) {
+        ret left(c);
+    }
+    if over_limit(opts.max_nesting, depth + 1u) {
+        ret right(limit_error("Command substitutions nested too deeply",
+                              opts.max_nesting));
+    }
+    let subs: [command_line] = [];
+    for s in texts {
+        alt parse_checked(tokenizer::tokenize(s), [], opts, depth + 1u) {
+          parsed(cl) { subs += [cl]; }
+          empty { }
+          continuation_required(_) {
+            ret right(error("Incomplete command substitution.", none));
+          }
+          error(e, _) { ret right(error(e, none)); }
+        }
+    }
+    ret left({substitutions: subs with c});
+}
+
+/*
+Function: referenced_variables
+
+Collect the variables a command line expands, e.g. to learn what
+environment it needs.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+The name of every $name and ${name...} in the commands' words and the
+redirections still in effect, including those inside $(...), in the
+order the commands appear and, within one, words before redirections.
+Duplicates are kept. An assignment such as A=$B references B but not A.
+Here-doc bodies and the bare names inside $((...)) are not searched.
+*/
+fn referenced_variables(cl: command_line) -> [str] {
+    ret fold_command_line(cl, []) {|names, c|
+        names + tokens_variables(command_tokens(c))
+    };
+}
+
+/*
+Enum: fd_op
+
+One step in setting up a command's descriptors.
+*/
+enum fd_op {
+    open_read(uint, token),    // n<file
+    open_write(uint, token),   // n>file, truncating it
+    open_append(uint, token),  // n>>file
+    read_here_doc(uint, tokenizer::here_document),
+    read_string(uint, token),  // n<<<word
+    copy_fd(uint, uint),       // the first becomes a copy of the second
+    close(uint),
+}
+
+// The descriptor that op sets up.
+fn op_fd(op: fd_op) -> uint {
+    ret alt op {
+      open_read(fd, _) | open_write(fd, _) | open_append(fd, _)
+      | read_string(fd, _) { fd }
+      read_here_doc(fd, _) { fd }
+      copy_fd(fd, _) { fd }
+      close(fd) { fd }
+    };
+}
+
+// The step for one redirection token, none for a word.
+fn token_op(t: token) -> option<fd_op> {
+    ret alt t {
+      tokenizer::redirect_input(w) { some(open_read(0u, *w)) }
+      tokenizer::here_doc(h) { some(read_here_doc(0u, h)) }
+      tokenizer::here_string(w) { some(read_string(0u, *w)) }
+      tokenizer::redirect_output(w) { some(open_write(1u, *w)) }
+      tokenizer::redirect_output_append(w) { some(open_append(1u, *w)) }
+      tokenizer::redirect_error(w) { some(open_write(2u, *w)) }
+      tokenizer::redirect_error_append(w) { some(open_append(2u, *w)) }
+      tokenizer::redirect_error_to_output { some(copy_fd(2u, 1u)) }
+      tokenizer::redirect_fd(fd, w) { some(open_write(fd, *w)) }
+      tokenizer::redirect_fd_input(fd, w) { some(open_read(fd, *w)) }
+      tokenizer::fd_here_doc(fd, h) { some(read_here_doc(fd, h)) }
+      tokenizer::fd_here_string(fd, w) { some(read_string(fd, *w)) }
+      tokenizer::dup_fd(fd, n) { some(copy_fd(fd, n)) }
+      tokenizer::close_fd(fd) { some(close(fd)) }
+      _ { none }
+    };
+}
+
+/*
+Function: normalize_redirections
+
+List the operations that set up a command's descriptors, e.g. for an
+executor to turn into open, dup2 and close calls.
+
+Parameters:
+
+c - A parsed command.
+open - The descriptors open before the command's redirections, usually
+       0, 1 and 2.
+
+Returns:
+
+The operations in the order to perform them, or an error if one copies
+a descriptor that is not open at that point. A redirection that a later
+one replaced, as the first of >a >b, has no operation. A copy of 2 onto
+1 comes before or after 1's own redirection as written, and every close
+comes last. A teed sink is set up as the sink it wraps; writing the log
+is left to the caller.
+*/
+fn normalize_redirections(c: command, open: [uint])
+    -> result<[fd_op], str> {
+    let ops: [fd_op] = [];
+    let open = open;
+    for t in command_tokens(c) {
+        alt token_op(t) {
+          some(op) {
+            alt op {
+              copy_fd(_, n) if !vec::contains(open, n) {
+                ret err("Descriptor " + uint::to_str(n, 10u)
+                        + " is not open.");
+              }
+              _ { }
+            }
+            let fd = op_fd(op);
+            let still_open: [uint] = [];
+            for o in open {
+                if o != fd { still_open += [o]; }
+            }
+            open = still_open;
+            alt op {
+              close(_) { }
+              _ { open += [fd]; }
+            }
+            ops += [op];
+          }
+          none { }
+        }
+    }
+    ret ok(ops);
+}
+
+fn node_count_in(cls: [command_line]) -> uint {
+    let n = 0u;
+    for cl in cls {
+        n += node_count(cl);
+    }
+    ret n;
+}
+
+/*
+Function: node_count
+
+Count the nodes in a command line, for diagnostics and complexity
+limits.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+One for every singleton, pipeline, sequence, background, and, or and
+negate node in the tree, including cl itself. A negated pipeline such
+as ! a | b counts the negation too, making 4.
+*/
+fn node_count(cl: command_line) -> uint {
+    ret 1u + alt cl {
+      singleton(_) { 0u }
+      pipeline(cls) { node_count_in(cls) }
+      sequence(cls) { node_count_in(cls) }
+      background(bg) { node_count(*bg) }
+      and(cls) { node_count_in(cls) }
+      or(cls) { node_count_in(cls) }
+      negate(cl) { node_count(*cl) }
+    };
+}
+
+fn depth_in(cls: [command_line]) -> uint {
+    let deepest = 0u;
+    for cl in cls {
+        deepest = uint::max(deepest, depth(cl));
+    }
+    ret deepest;
+}
+
+/*
+Function: depth
+
+Measure how deeply a command line is nested.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+The number of nodes on the longest path from cl down to a command. A
+lone command has depth 1. An empty sequence also has depth 1.
+*/
+fn depth(cl: command_line) -> uint {
+    ret 1u + alt cl {
+      singleton(_) { 0u }
+      pipeline(cls) { depth_in(cls) }
+      sequence(cls) { depth_in(cls) }
+      background(bg) { depth(*bg) }
+      and(cls) { depth_in(cls) }
+      or(cls) { depth_in(cls) }
+      negate(cl) { depth(*cl) }
+    };
+}
+
+/*
+Type: lint_warning
+
+A suspicious but legal construct found by <lint>.
+
+Fields:
+
+stage - The index of the offending command within its pipeline.
+message - A description of the problem.
+*/
+type lint_warning = {stage: uint, message: str};
+
+fn lint_pipeline(cls: [command_line]) -> [lint_warning] {
+    let warnings: [lint_warning] = [];
+    let stage = 0u;
+    while stage + 1u < vec::len(cls) {
+        alt cls[stage] {
+          // With 2>&1 before >f, the errors still go down the pipe.
+          singleton(c) if c.error == stdout
+                          || dup_target(c.fds, 2u) == some(1u) { }
+          singleton(c) {
+            alt sink_file(c.output) {
+              some(f) {
+                let m = "Stage " + uint::to_str(stage, 10u)
+                    + " writes its output to '" + f + "', so stage "
+                    + uint::to_str(stage + 1u, 10u) + " reads nothing.";
+                warnings += [{stage: stage, message: m}];
+              }
+              none { }
+            }
+          }
+          _ { }
+        }
+        stage += 1u;
+    }
+    ret warnings;
+}
+
+fn lint_all(cls: [command_line]) -> [lint_warning] {
+    let warnings: [lint_warning] = [];
+    for cl in cls {
+        warnings += lint(cl);
+    }
+    ret warnings;
+}
+
+/*
+Function: lint
+
+Look for constructs that parse but probably do not do what was meant.
+
+At present this reports pipeline stages whose standard output is
+redirected to a file, leaving the next stage with no input. A stage
+whose errors still go to the pipe, as in a 2>&1 >f | b, is not
+reported.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+The warnings found, in the order the commands appear.
+*/
+fn lint(cl: command_line) -> [lint_warning] {
+    ret alt cl {
+      singleton(_) { [] }
+      pipeline(cls) { lint_pipeline(cls) + lint_all(cls) }
+      sequence(cls) { lint_all(cls) }
+      background(bg) { lint(*bg) }
+      and(cls) { lint_all(cls) }
+      or(cls) { lint_all(cls) }
+      negate(cl) { lint(*cl) }
+    };
+}
+
+fn tee_sink(s: output_sink, log: str) -> output_sink {
+    ret alt s {
+      teed(_, l) if l == log { s }
+      _ { teed(@s, log) }
+    };
+}
+
+/*
+Function: tee_output
+
+Rewrite a command line so that everything its commands write is also
+appended to a log file.
+
+Parameters:
+
+cl - A parsed command line.
+log - The log file.
+
+Returns:
+
+A copy of cl in which every command's output and error sinks are
+wrapped in teed, keeping the original sink (and so whether it truncates
+or appends) inside. Sinks already teed to log are left alone, so the
+rewrite can be applied more than once.
+*/
+fn tee_output(cl: command_line, log: str) -> command_line {
+    ret map_commands(cl, {|c|
+        {output: tee_sink(c.output, log), error: tee_sink(c.error, log)
+         with c}
+    });
+}
+
+/*
+Function: with_args
+
+Replace a command's arguments.
+
+Parameters:
+
+c - A command.
+args - The new arguments.
+
+Returns:
+
+c with args in place of its arguments and its redirections untouched.
+*/
+fn with_args(c: command, args: [token]) -> command {
+    ret {args: args with c};
+}
+
+fn map_all(cls: [command_line], f: fn(command) -> command)
+    -> [command_line] {
+    let mapped: [command_line] = [];
+    for cl in cls {
+        mapped += [map_commands(cl, f)];
+    }
+    ret mapped;
+}
+
+/*
+Function: map_commands
+
+Rewrite every simple command in a command line.
+
+Parameters:
+
+cl - A parsed command line.
+f - Called on each command, in the order the commands appear.
+
+Returns:
+
+A copy of cl with each command replaced by what f returns for it. The
+shape of the tree is unchanged.
+*/
+fn map_commands(cl: command_line, f: fn(command) -> command)
+    -> command_line {
+    ret alt cl {
+      singleton(c) { singleton(f(c)) }
+      pipeline(cls) { pipeline(map_all(cls, f)) }
+      sequence(cls) { sequence(map_all(cls, f)) }
+      background(bg) { background(@map_commands(*bg, f)) }
+      and(cls) { and(map_all(cls, f)) }
+      or(cls) { or(map_all(cls, f)) }
+      negate(cl) { negate(@map_commands(*cl, f)) }
+    };
+}
+
+/*
+Function: map_args
+
+Rewrite the arguments of every simple command in a command line, e.g.
+to inject an option.
+
+Parameters:
+
+cl - A parsed command line.
+f - Called on each command's arguments.
+
+Returns:
+
+A copy of cl in which each command has the arguments f returns for it
+and keeps its redirections.
+*/
+fn map_args(cl: command_line, f: fn([token]) -> [token]) -> command_line {
+    ret map_commands(cl, {|c| with_args(c, f(c.args))});
+}
+
+fn fold_all<T: copy>(cls: [command_line], init: T, f: fn(T, command) -> T)
+    -> T {
+    let acc = init;
+    for cl in cls {
+        acc = fold_command_line(cl, acc, f);
+    }
+    ret acc;
+}
+
+/*
+Function: fold_command_line
+
+Combine every simple command in a command line into one value, e.g. to
+collect program names.
+
+Parameters:
+
+cl - A parsed command line.
+init - The starting value.
+f - Called with the value so far and each command, in the order the
+    commands appear.
+
+Returns:
+
+What f returns for the last command, or init if there are none.
+*/
+fn fold_command_line<T: copy>(cl: command_line, init: T,
+                              f: fn(T, command) -> T) -> T {
+    ret alt cl {
+      singleton(c) { f(init, c) }
+      pipeline(cls) { fold_all(cls, init, f) }
+      sequence(cls) { fold_all(cls, init, f) }
+      background(bg) { fold_command_line(*bg, init, f) }
+      and(cls) { fold_all(cls, init, f) }
+      or(cls) { fold_all(cls, init, f) }
+      negate(cl) { fold_command_line(*cl, init, f) }
+    };
+}
+
+/*
+Enum: connector
+
+How a <step> depends on the exit status of the steps before it.
+
+begin - The first step; always run.
+on_success - Run only if the previous status is zero (&&).
+on_failure - Run only if the previous status is non-zero (||).
+unconditional - Always run (;).
+*/
+enum connector {
+    begin,
+    on_success,
+    on_failure,
+    unconditional,
+}
+
+type step = {connector: connector, cl: command_line};
+
+fn steps_of_list(cls: [command_line], first: connector, rest: connector)
+    -> [step] {
+    let steps: [step] = [];
+    let conn = first;
+    for cl in cls {
+        steps += steps_of(cl, conn);
+        conn = rest;
+    }
+    ret steps;
+}
+
+fn steps_of(cl: command_line, conn: connector) -> [step] {
+    // A list can only be opened up when it runs regardless of the status
+    // so far; "a && (b || c)" must skip all of "b || c" when a fails.
+    let flatten = alt conn {
+      begin | unconditional { true }
+      _ { false }
+    };
+    if !flatten {
+        ret [{connector: conn, cl: cl}];
+    }
+    ret alt cl {
+      and(cls) { steps_of_list(cls, conn, on_success) }
+      or(cls) { steps_of_list(cls, conn, on_failure) }
+      sequence(cls) { steps_of_list(cls, conn, unconditional) }
+      _ { [{connector: conn, cl: cl}] }
+    };
+}
+
+/*
+Function: and_or_steps
+
+Split a command line into the operands of its top-level and/or lists and
+sequences.
+
+Running the steps in order, and skipping each one whose connector does
+not match the exit status of the last step that ran, gives the same
+result as running the whole command line. Pipelines, background jobs and
+lists nested where they might be skipped as a whole are single steps.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+The steps, in execution order.
+*/
+fn and_or_steps(cl: command_line) -> [step] {
+    ret steps_of(cl, begin);
+}
+
+// A word as written, between backquotes.
+fn backquote(w: token) -> str {
+    ret "`" + tokenizer::word_text(w) + "`";
+}
+
+fn sink_phrase(s: output_sink, what: str) -> option<str> {
+    ret alt s {
+      stdout { none }
+      stderr { some("sending " + what + " to standard error") }
+      outfile(f) { some("writing " + what + " to " + backquote(f)) }
+      appendfile(f) { some("appending " + what + " to " + backquote(f)) }
+      teed(inner, log) {
+        let copy = "appending a copy of " + what + " to `" + log + "`";
+        alt sink_phrase(*inner, what) {
+          some(p) { some(p + " and " + copy) }
+          none { some(copy) }
+        }
+      }
+    };
+}
+
+fn describe_command(c: command) -> str {
+    let d = if vec::is_empty(c.args) { "nothing" } else {
+        backquote(c.args[0u])
+    };
+    if vec::len(c.args) > 1u {
+        let args = vec::slice(c.args, 1u, vec::len(c.args));
+        let quoted: [str] = [];
+        for a in args {
+            quoted += [backquote(a)];
+        }
+        let label = if vec::len(args) == 1u { " with argument " } else {
+            " with arguments "
+        };
+        d += label + str::connect(quoted, " ");
+    }
+    let clauses: [str] = [];
+    alt c.input {
+      stdin { }
+      infile(f) { clauses += ["reading input from " + backquote(f)]; }
+      here_doc_input(_) { clauses += ["reading input from a here-document"]; }
+      herestring(w) {
+        clauses += ["reading input from the string " + backquote(w)];
+      }
+    }
+    alt sink_phrase(c.output, "output") {
+      some(p) { clauses += [p]; }
+      none { }
+    }
+    if c.error == c.output && c.error != stderr {
+        clauses += ["sending errors along with its output"];
+    } else {
+        alt sink_phrase(c.error, "errors") {
+          some(p) { clauses += [p]; }
+          none { }
+        }
+    }
+    for r in c.fds {
+        let fd = "descriptor " + uint::to_str(r.fd, 10u);
+        clauses += [alt r.target {
+          fd_infile(f) { "reading " + fd + " from " + backquote(f) }
+          fd_outfile(f) { "writing " + fd + " to " + backquote(f) }
+          fd_here_doc_input(_) { "reading " + fd + " from a here-document" }
+          fd_herestring(w) {
+            "reading " + fd + " from the string " + backquote(w)
+          }
+          fd_dup(n) {
+            "pointing " + fd + " at descriptor " + uint::to_str(n, 10u)
+          }
+        }];
+    }
+    for fd in c.closed {
+        clauses += ["closing descriptor " + uint::to_str(fd, 10u)];
+    }
+    ret str::connect([d] + clauses, ", ");
+}
+
+// Nested lists are wrapped in parentheses so that where each one ends is
+// not left to the reader.
+fn explain_nested(cl: command_line) -> str {
+    ret alt cl {
+      singleton(_) { explain_cl(cl) }
+      _ { "(" + explain_cl(cl) + ")" }
+    };
+}
+
+fn explain_list(cls: [command_line], connector: str) -> str {
+    let parts: [str] = [];
+    for cl in cls {
+        parts += [explain_nested(cl)];
+    }
+    ret str::connect(parts, connector);
+}
+
+fn explain_cl(cl: command_line) -> str {
+    ret alt cl {
+      singleton(c) { "run " + describe_command(c) }
+      pipeline(cls) {
+        let d = explain_nested(cls[0u]);
+        for stage in vec::slice(cls, 1u, vec::len(cls)) {
+            d += ", piping its output into " + alt stage {
+              singleton(c) { describe_command(c) }
+              _ { explain_nested(stage) }
+            };
+        }
+        d
+      }
+      sequence(cls) { explain_list(cls, "; then ") }
+      background(bg) { explain_nested(*bg) + ", in the background" }
+      and(cls) { explain_list(cls, "; if that succeeds, ") }
+      or(cls) { explain_list(cls, "; if that fails, ") }
+      negate(cl) { explain_nested(*cl) + ", inverting its exit status" }
+    };
+}
+
+/*
+Function: explain
+
+Describe a command line in plain English, for learning tools.
+
+Parameters:
+
+cmd_line - The command line to describe.
+
+Returns:
+
+A description such as "run `ls` with argument `-l`, piping its output
+into `wc`". Each command is named with its arguments and redirections;
+pipelines, lists and background jobs are spelled out around them. Input
+that does not parse is described as such.
+*/
+fn explain(cmd_line: str) -> str {
+    ret alt parse(tokenizer::tokenize(cmd_line)) {
+      parsed(cl) { explain_cl(cl) }
+      continuation_required(_) { "an incomplete command line" }
+      error(e, _) { "a command line that does not parse: " + e }
+      empty { "nothing" }
+    };
+}
+
+// The redirection that points descriptor 1, or 2 if is_error, at s, or
+// none if s is where that descriptor already goes.
+fn sink_token(s: output_sink, is_error: bool) -> option<token> {
+    ret alt s {
+      stdout {
+        if is_error { some(tokenizer::redirect_error_to_output) } else {
+            none
+        }
+      }
+      stderr { if is_error { none } else { some(tokenizer::dup_fd(1u, 2u)) } }
+      outfile(f) {
+        some(if is_error { tokenizer::redirect_error(@f) } else {
+            tokenizer::redirect_output(@f)
+        })
+      }
+      appendfile(f) {
+        some(if is_error { tokenizer::redirect_error_append(@f) } else {
+            tokenizer::redirect_output_append(@f)
+        })
+      }
+      teed(inner, _) { sink_token(*inner, is_error) }
+    };
+}
+
+fn command_tokens(c: command) -> [token] {
+    let ts = c.args;
+    alt c.input {
+      stdin { }
+      infile(f) { ts += [tokenizer::redirect_input(@f)]; }
+      here_doc_input(h) { ts += [tokenizer::here_doc(h)]; }
+      herestring(w) { ts += [tokenizer::here_string(@w)]; }
+    }
+    let out = sink_token(c.output, false);
+    let err = if c.error == c.output && c.error != stderr {
+        some(tokenizer::redirect_error_to_output)
+    } else {
+        sink_token(c.error, true)
+    };
+    // 2>&1 copies wherever descriptor 1 points when it is written, so
+    // errors that go to standard output must come before >file.
+    let err_first = c.error != c.output
+        && err == some(tokenizer::redirect_error_to_output);
+    let sinks = if err_first { [err, out] } else { [out, err] };
+    for s in sinks {
+        alt s {
+          some(t) { ts += [t]; }
+          none { }
+        }
+    }
+    for r in c.fds {
+        ts += [alt r.target {
+          fd_infile(f) { tokenizer::redirect_fd_input(r.fd, @f) }
+          fd_outfile(f) { tokenizer::redirect_fd(r.fd, @f) }
+          fd_here_doc_input(h) { tokenizer::fd_here_doc(r.fd, h) }
+          fd_herestring(w) { tokenizer::fd_here_string(r.fd, @w) }
+          fd_dup(n) { tokenizer::dup_fd(r.fd, n) }
+        }];
+    }
+    for fd in c.closed {
+        ts += [tokenizer::close_fd(fd)];
+    }
+    ret ts;
+}
+
+// How tightly each kind of node binds, from lists up to single commands.
+fn precedence(cl: command_line) -> uint {
+    ret alt cl {
+      sequence(_) | background(_) { 0u }
+      and(_) | or(_) { 1u }
+      negate(_) { 2u }
+      pipeline(_) { 3u }
+      singleton(_) { 4u }
+    };
+}
+
+// The tokens for cl, in a subshell if it binds less tightly than min.
+// Operands to the right of && and || are parenthesized even at the same
+// level, since the parser groups from the left.
+fn cl_tokens(cl: command_line, min: uint) -> [token] {
+    if precedence(cl) < min {
+        ret [tokenizer::open_subshell] + cl_tokens(cl, 0u)
+            + [tokenizer::close_subshell];
+    }
+    ret alt cl {
+      singleton(c) { command_tokens(c) }
+      pipeline(cls) { join_tokens(cls, tokenizer::pipe, 4u, 4u) }
+      and(cls) { join_tokens(cls, tokenizer::and, 1u, 2u) }
+      or(cls) { join_tokens(cls, tokenizer::or, 1u, 2u) }
+      negate(n) { [tokenizer::negate] + cl_tokens(*n, 2u) }
+      background(bg) { cl_tokens(*bg, 1u) + [tokenizer::background] }
+      sequence(cls) {
+        let ts: [token] = [];
+        let i = 0u;
+        while i < vec::len(cls) {
+            ts += alt cls[i] {
+              background(_) { cl_tokens(cls[i], 1u) }
+              // A list of one keeps its ; so that it parses back the same.
+              _ if i + 1u < vec::len(cls) || vec::len(cls) == 1u {
+                cl_tokens(cls[i], 1u) + [tokenizer::sequence]
+              }
+              _ { cl_tokens(cls[i], 1u) }
+            };
+            i += 1u;
+        }
+        ts
+      }
+    };
+}
+
+fn join_tokens(cls: [command_line], op: token, first_min: uint,
+               rest_min: uint) -> [token] {
+    let ts = cl_tokens(cls[0u], first_min);
+    for cl in vec::slice(cls, 1u, vec::len(cls)) {
+        ts += [op] + cl_tokens(cl, rest_min);
+    }
+    ret ts;
+}
+
+/*
+Function: command_line_to_string
+
+Render a parsed command line back into shell syntax, to show what the
+parser understood.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+A command line that parses back to cl. Parentheses are added only where
+the nesting would otherwise be lost, and every command's redirections
+are written after its arguments. Each word is written with the quoting
+it was parsed with, so an expansion comes back as an expansion. A teed
+sink has no shell syntax, so only the sink inside it is written. Only
+the redirections still in effect are written, so the files of the
+command line parsed back may be fewer or in another order.
+*/
+fn command_line_to_string(cl: command_line) -> str {
+    ret tokenizer::detokenize(cl_tokens(cl, 0u));
+}
+
+fn json_string(s: str) -> str {
+    let r = "\"";
+    for ch in str::chars(s) {
+        alt ch {
+          '"' | '\\' {
+            str::push_char(r, '\\');
+            str::push_char(r, ch);
+          }
+          '\n' { r += "\\n"; }
+          '\t' { r += "\\t"; }
+          '\r' { r += "\\r"; }
+          _ if (ch as uint) < 0x20u {
+            r += #fmt("\\u%04x", ch as uint);
+          }
+          _ { str::push_char(r, ch); }
+        }
+    }
+    ret r + "\"";
+}
+
+// Fields are written in the order given, so the output is stable.
+fn json_object(fields: [(str, str)]) -> str {
+    let parts = vec::map(fields) {|f|
+        let (k, v) = f;
+        json_string(k) + ":" + v
+    };
+    ret "{" + str::connect(parts, ",") + "}";
+}
+
+fn json_list(items: [str]) -> str {
+    ret "[" + str::connect(items, ",") + "]";
+}
+
+fn json_word(w: token) -> str {
+    ret json_string(tokenizer::word_text(w));
+}
+
+fn json_kind(kind: str, fields: [(str, str)]) -> str {
+    ret json_object([("kind", json_string(kind))] + fields);
+}
+
+fn json_bool(b: bool) -> str {
+    ret if b { "true" } else { "false" };
+}
+
+fn here_doc_fields(h: tokenizer::here_document) -> [(str, str)] {
+    ret [("delimiter", json_string(h.delimiter)),
+         ("body", json_string(h.body)), ("quoted", json_bool(h.quoted)),
+         ("strip_tabs", json_bool(h.strip_tabs))];
+}
+
+fn input_to_json(i: input_source) -> str {
+    ret alt i {
+      stdin { json_kind("stdin", []) }
+      infile(f) { json_kind("infile", [("file", json_word(f))]) }
+      here_doc_input(h) { json_kind("here_doc", here_doc_fields(h)) }
+      herestring(w) { json_kind("herestring", [("word", json_word(w))]) }
+    };
+}
+
+fn sink_to_json(s: output_sink) -> str {
+    ret alt s {
+      stdout { json_kind("stdout", []) }
+      stderr { json_kind("stderr", []) }
+      outfile(f) { json_kind("outfile", [("file", json_word(f))]) }
+      appendfile(f) { json_kind("appendfile", [("file", json_word(f))]) }
+      teed(inner, log) {
+        json_kind("teed", [("sink", sink_to_json(*inner)),
+                           ("log", json_string(log))])
+      }
+    };
+}
+
+fn fd_redirect_to_json(r: fd_redirect) -> str {
+    let target = alt r.target {
+      fd_infile(f) { json_kind("fd_infile", [("file", json_word(f))]) }
+      fd_outfile(f) { json_kind("fd_outfile", [("file", json_word(f))]) }
+      fd_here_doc_input(h) { json_kind("fd_here_doc", here_doc_fields(h)) }
+      fd_herestring(w) {
+        json_kind("fd_herestring", [("word", json_word(w))])
+      }
+      fd_dup(n) { json_kind("fd_dup", [("fd", uint::to_str(n, 10u))]) }
+    };
+    ret json_object([("fd", uint::to_str(r.fd, 10u)), ("target", target)]);
+}
+
+fn children_to_json(kind: str, cls: [command_line]) -> str {
+    ret json_kind(kind, [("children",
+                          json_list(vec::map(cls, command_line_to_json)))]);
+}
+
+/*
+Function: command_line_to_json
+
+Serialize a parsed command line as JSON, for editor integrations and
+test snapshots.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+A JSON object for cl, written on one line. Every node has a "kind": a
+singleton carries "args", "input", "output", "error", "fds" and
+"closed"; a pipeline, sequence, and or or has "children"; and a
+background or negate has a "child". Sources and sinks are objects with a
+"kind" and their payload, e.g. {"kind":"outfile","file":"log"}. Keys
+always come in the same order, so the output can be compared as text.
+*/
+fn command_line_to_json(cl: command_line) -> str {
+    ret alt cl {
+      singleton(c) {
+        json_kind("singleton",
+                  [("args", json_list(vec::map(c.args, json_word))),
+                   ("input", input_to_json(c.input)),
+                   ("output", sink_to_json(c.output)),
+                   ("error", sink_to_json(c.error)),
+                   ("fds", json_list(vec::map(c.fds, fd_redirect_to_json))),
+                   ("closed", json_list(vec::map(c.closed) {|fd|
+                       uint::to_str(fd, 10u)
+                   }))])
+      }
+      pipeline(cls) { children_to_json("pipeline", cls) }
+      sequence(cls) { children_to_json("sequence", cls) }
+      and(cls) { children_to_json("and", cls) }
+      or(cls) { children_to_json("or", cls) }
+      background(bg) {
+        json_kind("background", [("child", command_line_to_json(*bg))])
+      }
+      negate(n) { json_kind("negate", [("child", command_line_to_json(*n))]) }
+    };
+}
+
+/*
+Type: schedule_node
+
+One element of a sequence in a <dependency_graph>.
+
+Fields:
+
+cl - The element.
+after - The indices of the earlier elements that must finish before it
+        starts, in increasing order.
+*/
+type schedule_node = {cl: command_line, after: [uint]};
+
+// Redirection targets plus every argument that might name a file. Program
+// names and options are left out.
+fn touched_names(cl: command_line) -> [str] {
+    ret fold_command_line(cl, []) {|names, c|
+        let touched = names + c.files;
+        let i = 1u;
+        while i < vec::len(c.args) {
+            let a = tokenizer::word_text(c.args[i]);
+            if !str::starts_with(a, "-") {
+                touched += [a];
+            }
+            i += 1u;
+        }
+        touched
+    };
+}
+
+fn shares_name(a: [str], b: [str]) -> bool {
+    for n in a {
+        if vec::contains(b, n) {
+            ret true;
+        }
+    }
+    ret false;
+}
+
+/*
+Function: dependency_graph
+
+Work out which elements of a sequence could run at the same time, for
+embedders that schedule commands like a build tool. A shell always runs a
+sequence in order, so this is only advice.
+
+Two elements depend on each other when they share a name: a redirection
+target, or an argument other than the program name that does not start
+with "-". This errs towards ordering commands that merely mention the
+same word.
+
+Parameters:
+
+cl - A parsed command line.
+
+Returns:
+
+One node per element of a top-level sequence, in order, or a single node
+for any other command line. Each node lists the earlier nodes it must
+wait for, so the edges always point backwards and the graph is acyclic.
+*/
+fn dependency_graph(cl: command_line) -> [schedule_node] {
+    let cls = alt cl {
+      sequence(cls) { cls }
+      _ { [cl] }
+    };
+    let names: [[str]] = [];
+    let nodes: [schedule_node] = [];
+    for c in cls {
+        let touched = touched_names(c);
+        let after: [uint] = [];
+        let i = 0u;
+        while i < vec::len(names) {
+            if shares_name(touched, names[i]) {
+                after += [i];
+            }
+            i += 1u;
+        }
+        names += [touched];
+        nodes += [{cl: c, after: after}];
+    }
+    ret nodes;
+}
+
+/*
+Type: statement_stream
+
+Input fed to <feed_statements> that does not yet make up a whole
+statement.
+
+Fields:
+
+pending - The input kept for later statements.
+taken - How many characters of input came before pending.
+*/
+type statement_stream = {pending: str, taken: uint};
+
+/*
+Function: new_statement_stream
+
+Start reading statements from input that arrives in pieces, e.g. from a
+pipe or a terminal.
+
+Returns:
+
+A statement_stream with nothing pending.
+*/
+fn new_statement_stream() -> statement_stream {
+    ret {pending: "", taken: 0u};
+}
+
+// Tokenizer errors for a quote, ${, $( or $(( that is still open, which
+// later input may close.
+fn unclosed(msg: str) -> bool {
+    ret str::starts_with(msg, "Missing ");
+}
+
+// Whether the input after the last token, c[from] onward, finishes its
+// line. Blanks and line joins are skipped, and a comment or here-doc body
+// there only counts once a newline has followed it.
+fn ends_line(c: [char], from: uint) -> bool {
+    let i = from;
+    while i < vec::len(c) {
+        if c[i] == ' ' || c[i] == '\t' {
+            i += 1u;
+        } else if c[i] == '\\' && i + 1u < vec::len(c) && c[i + 1u] == '\n' {
+            i += 2u;
+        } else {
+            break;
+        }
+    }
+    ret vec::contains(vec::slice(c, i, vec::len(c)), '\n');
+}
+
+/*
+Function: feed_statements
+
+Add input to a statement stream and take out the top-level statements it
+completes.
+
+A statement is complete once the ;, & or newline that ends it has been
+seen. Until then its input is kept, so words, quotes, here-docs and line
+continuations may be split across chunks anywhere. A & is only taken to
+end a statement once the next character shows that it is not part of &&
+or &>, and the statements on a line with a here-doc wait for its body. A
+complete statement that does not parse is reported and its input
+dropped, so the statements after it still come out.
+
+Parameters:
+
+stream - The stream; updated to hold whatever input is left over.
+chunk - The next piece of input.
+
+Returns:
+
+A parsed or error result for each completed statement, in order. Blank
+lines produce none. The offset of a tokenizer error counts characters
+from the start of all the input fed to the stream.
+*/
+fn feed_statements(&stream: statement_stream, chunk: str)
+    -> [parse_result] {
+    // Nothing more can end until a terminator arrives, or the character
+    // after a pending & shows what it is, so until then the input is
+    // only stored.
+    let terminates = str::ends_with(stream.pending, "&");
+    for ch in str::chars(chunk) {
+        if ch == ';' || ch == '&' || ch == '\n' {
+            terminates = true;
+        }
+    }
+    stream = {pending: stream.pending + chunk with stream};
+    ret if terminates { take_statements(stream) } else { [] };
+}
+
+// Tokenizes the pending input once and takes out the statements it
+// completes.
+fn take_statements(&stream: statement_stream) -> [parse_result] {
+    let c = str::chars(stream.pending);
+    let n = vec::len(c);
+    let tokens = tokenizer::tokenize_spans(tokenizer::default_config(),
+                                           stream.pending);
+    let results: [parse_result] = [];
+    // The statements on a line with a here-doc, which are held until the
+    // line ends.
+    let line: [parse_result] = [];
+    let has_doc = false;
+    let stmt: [token] = [];
+    // Where the input that has not been taken out starts.
+    let cut = 0u;
+
+    #macro([#keep[],
+            {
+                stream = {pending: str::from_chars(vec::slice(c, cut, n)),
+                          taken: stream.taken + cut};
+                ret results;
+            }]);
+
+    let i = 0u;
+    while i < vec::len(tokens) {
+        let (t, sp) = tokens[i];
+        alt t {
+          tokenizer::error(e, at) {
+            // Anything but an open quote is there to stay once its line
+            // has ended.
+            let nl = at;
+            while nl < n && c[nl] != '\n' {
+                nl += 1u;
+            }
+            if unclosed(e) || nl == n {
+                #keep[];
+            }
+            results += line + [error(e, some(stream.taken + at))];
+            stream = {pending: str::from_chars(vec::slice(c, nl + 1u, n)),
+                      taken: stream.taken + nl + 1u};
+            ret results + take_statements(stream);
+          }
+          tokenizer::sequence | tokenizer::background {
+            if t == tokenizer::background && sp.end == n {
+                #keep[];
+            }
+            let r = if t == tokenizer::background {
+                parse(stmt + [t])
+            } else if vec::is_empty(stmt) && c[sp.start] == ';' {
+                // A ; with no command before it, as in "a ;; b".
+                parse([t])
+            } else {
+                parse(stmt)
+            };
+            alt r {
+              empty { }
+              continuation_required(_) { #keep[]; }
+              _ { line += [r]; }
+            }
+            stmt = [];
+            if c[sp.start] == '\n' || !has_doc {
+                results += line;
+                line = [];
+                has_doc = false;
+                cut = if i + 1u < vec::len(tokens) {
+                    let (_, next) = tokens[i + 1u];
+                    next.start
+                } else {
+                    sp.end
+                };
+            }
+          }
+          tokenizer::continuation(_) { #keep[]; }
+          tokenizer::here_doc(_) | tokenizer::fd_here_doc(_, _) {
+            has_doc = true;
+            stmt += [t];
+          }
+          _ { stmt += [t]; }
+        }
+        i += 1u;
+    }
+    // The tokenizer drops a newline at the very end, so the last
+    // statement is ended by what follows its last token. A here-doc
+    // delimiter only counts once its line is complete.
+    let last_end = if vec::is_empty(tokens) { 0u } else {
+        let (_, sp) = vec::last(tokens);
+        sp.end
+    };
+    let at_eol = n > 0u && c[n - 1u] == '\n';
+    if ends_line(c, last_end) && (at_eol || !has_doc) {
+        alt parse(stmt) {
+          empty { }
+          continuation_required(_) { #keep[]; }
+          r { line += [r]; }
+        }
+        results += line;
+        cut = n;
+        while !at_eol && c[cut - 1u] != '\n' {
+            cut -= 1u;
+        }
+    }
+    stream = {pending: str::from_chars(vec::slice(c, cut, n)),
+              taken: stream.taken + cut};
+    ret results;
+}
+
+/*
+Function: finish_statements
+
+Parse whatever is left in a statement stream once the input has ended.
+
+Parameters:
+
+stream - The stream.
+
+Returns:
+
+The parse of the final, unterminated statement, as for <parse>: empty if
+there is none, and continuation_required if the input stopped in the
+middle of one. An error offset counts from the start of the stream's
+input, as for <feed_statements>.
+*/
+fn finish_statements(stream: statement_stream) -> parse_result {
+    ret alt parse(tokenizer::tokenize(stream.pending)) {
+      error(e, some(at)) { error(e, some(stream.taken + at)) }
+      r { r }
+    };
+}
+
+#[test]
+fn test_token_cursor() {
+    let cur = new_cursor([tokenizer::string("a"), tokenizer::pipe]);
+    assert !at_end(cur);
+    assert peek(cur) == some(tokenizer::string("a"));
+    assert peek(cur) == some(tokenizer::string("a"));
+    assert next(cur) == some(tokenizer::string("a"));
+    assert peek(cur) == some(tokenizer::pipe);
+    assert next(cur) == some(tokenizer::pipe);
+    assert at_end(cur);
+    assert peek(cur) == none;
+    assert next(cur) == none;
+    assert at_end(new_cursor([]));
 }
 
 #[test]
 fn test_make_command() {
     assert make_command([tokenizer::string("foo"),
                          tokenizer::string("bar"),
-                         tokenizer::redirect_output("baz"),
+                         tokenizer::redirect_output(@tokenizer::string("baz")),
                          tokenizer::redirect_error_to_output])
-        == left({args: ["foo", "bar"],
+        == left({args: [literal("foo"), literal("bar")],
                  input: stdin,
-                 output: outfile("baz"),
-                 error: outfile("baz")});
+                 output: outfile(literal("baz")),
+                 error: outfile(literal("baz")),
+                 fds: [],
+                 closed: [],
+                 files: ["baz"],
+                 substitutions: []});
     assert make_command([tokenizer::string("foo"),
                          tokenizer::string("bar"),
                          tokenizer::redirect_error_to_output,
-                         tokenizer::redirect_output("baz")])
-        == left({args: ["foo", "bar"],
+                         tokenizer::redirect_output(@tokenizer::string("baz"))])
+        == left({args: [literal("foo"), literal("bar")],
                  input: stdin,
-                 output: outfile("baz"),
-                 error: stdout});
+                 output: outfile(literal("baz")),
+                 error: stdout,
+                 fds: [],
+                 closed: [],
+                 files: ["baz"],
+                 substitutions: []});
     assert make_command([tokenizer::string("foo"),
                          tokenizer::string("bar"),
-                         tokenizer::redirect_input("hootenanny"),
-                         tokenizer::redirect_output("baz")])
-        == left({args: ["foo", "bar"],
-                 input: infile("hootenanny"),
-                 output: outfile("baz"),
-                 error: stderr});
+                         tokenizer::redirect_input(
+                             @tokenizer::string("hootenanny")),
+                         tokenizer::redirect_output(@tokenizer::string("baz"))])
+        == left({args: [literal("foo"), literal("bar")],
+                 input: infile(literal("hootenanny")),
+                 output: outfile(literal("baz")),
+                 error: stderr,
+                 fds: [],
+                 closed: [],
+                 files: ["hootenanny", "baz"],
+                 substitutions: []});
     assert make_command([tokenizer::string("foo"),
                          tokenizer::string("bar"),
                          tokenizer::redirect_error_to_output,
-                         tokenizer::redirect_error("/dev/null"),
-                         tokenizer::redirect_output("baz")])
+                         tokenizer::redirect_error(
+                             @tokenizer::string("/dev/null")),
+                         tokenizer::redirect_output(@tokenizer::string("baz"))])
         == right("Multiple error redirects.");
     alt make_command([tokenizer::string("foo"),
                       tokenizer::string("bar"),
@@ -299,10 +2551,14 @@ fn test_make_command() {
 #[test]
 fn simple_cmdline() {
     assert parse(tokenizer::tokenize("  hi there"))
-        == parsed(singleton({args: ["hi", "there"],
+        == parsed(singleton({args: [literal("hi"), literal("there")],
                              input: stdin,
                              output: stdout,
-                             error: stderr}));
+                             error: stderr,
+                             fds: [],
+                             closed: [],
+                             files: [],
+                             substitutions: []}));
 }
 
 #[test]
@@ -310,49 +2566,1235 @@ fn complex_pipeline() {
     assert parse(tokenizer::tokenize("(cat abc d\"e f\\\"\"g; echo 'hello\\') |"
                                      + " grep -i he >matches &"))
         == parsed(background(@pipeline(
-            [sequence([singleton({args: ["cat", "abc", "de f\"g"],
+            [sequence([singleton({args: [literal("cat"), literal("abc"),
+                                         literal("de f\"g")],
                                   input: stdin,
                                   output: stdout,
-                                  error: stderr}),
-                       singleton({args: ["echo", "hello\\"],
+                                  error: stderr,
+                                  fds: [],
+                                  closed: [],
+                                  files: [],
+                                  substitutions: []}),
+                       singleton({args: [literal("echo"), literal("hello\\")],
                                   input: stdin,
                                   output: stdout,
-                                  error: stderr})]),
-             singleton({args: ["grep", "-i", "he"],
+                                  error: stderr,
+                                  fds: [],
+                                  closed: [],
+                                  files: [],
+                                  substitutions: []})]),
+             singleton({args: [literal("grep"), literal("-i"), literal("he")],
                         input: stdin,
-                        output: outfile("matches"),
-                        error: stderr})])));
+                        output: outfile(literal("matches")),
+                        error: stderr,
+                        fds: [],
+                        closed: [],
+                        files: ["matches"],
+                        substitutions: []})])));
 }
 
 #[test]
 fn test_continuation() {
+    assert parse(tokenizer::tokenize("echo $((1 +"))
+        == continuation_required("arith>");
     assert parse(tokenizer::tokenize("foo && bar && \\"))
-        == continuation_required;
+        == continuation_required("");
 }
 
 #[test]
 fn test_binary_operators() {
     alt parse(tokenizer::tokenize("foo && | bar")) {
-      error(_) { assert true; }
+      error(_, _) { assert true; }
       _ { assert false; }
     }
 
     assert parse(tokenizer::tokenize("foo && bar && baz"))
-        == parsed(and([singleton({args: ["foo"],
+        == parsed(and([singleton({args: [literal("foo")],
                                   input: stdin,
                                   output: stdout,
-                                  error: stderr}),
-                       singleton({args: ["bar"],
+                                  error: stderr,
+                                  fds: [],
+                                  closed: [],
+                                  files: [],
+                                  substitutions: []}),
+                       singleton({args: [literal("bar")],
                                   input: stdin,
                                   output: stdout,
-                                  error: stderr}),
-                       singleton({args: ["baz"],
+                                  error: stderr,
+                                  fds: [],
+                                  closed: [],
+                                  files: [],
+                                  substitutions: []}),
+                       singleton({args: [literal("baz")],
                                   input: stdin,
                                   output: stdout,
-                                  error: stderr})]));
+                                  error: stderr,
+                                  fds: [],
+                                  closed: [],
+                                  files: [],
+                                  substitutions: []})]));
 
     alt parse(tokenizer::tokenize("foo && bar &&")) {
-      error(_) { assert true; }
+      error(_, _) { assert true; }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_referenced_files() {
+    alt parse(tokenizer::tokenize("cat < in.txt 2> err.log | sort > out.txt"
+                                  + " 2>&1 | uniq > final.txt")) {
+      parsed(cl) {
+        assert referenced_files(cl)
+            == ["in.txt", "err.log", "out.txt", "final.txt"];
+      }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("(a > x; b < y) && c")) {
+      parsed(cl) { assert referenced_files(cl) == ["x", "y"]; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("sort 2>err <in >out")) {
+      parsed(cl) { assert referenced_files(cl) == ["err", "in", "out"]; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("cat <f | tee f >>f && ! wc 3<f")) {
+      parsed(cl) { assert referenced_files(cl) == ["f", "f", "f"]; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("cmd >f 2>f")) {
+      parsed(cl) { assert referenced_files(cl) == ["f", "f"]; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("cmd 2>e <i")) {
+      parsed(cl) { assert referenced_files(cl) == ["e", "i"]; }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_program_names() {
+    alt parse(tokenizer::tokenize("ls -l | (grep a; sort -r) | wc && ls")) {
+      parsed(cl) {
+        assert program_names(cl) == ["ls", "grep", "sort", "wc", "ls"];
+      }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize(">out; ! (a &) || b")) {
+      parsed(cl) { assert program_names(cl) == ["a", "b"]; }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_subshell_trailing_operator() {
+    assert parse(tokenizer::tokenize("(a &&)"))
+        == error("Missing command before ')'.", none);
+    assert parse(tokenizer::tokenize("(a |)"))
+        == error("Missing command before ')'.", none);
+    assert parse(tokenizer::tokenize("()"))
+        == error("Missing command before ')'.", none);
+    assert parse(tokenizer::tokenize("a &&"))
+        == error("Missing command at end of line.", none);
+    assert parse(tokenizer::tokenize("(a;)"))
+        == parsed(sequence([singleton({args: [literal("a")],
+                                       input: stdin,
+                                       output: stdout,
+                                       error: stderr,
+                                       fds: [],
+                                       closed: [],
+                                       files: [],
+                                       substitutions: []})]));
+}
+
+#[test]
+fn test_strict_supported() {
+    let ts = tokenizer::tokenize("a | b &");
+    let all = {strict_supported: some([pipelines, background_jobs])
+               with default_options()};
+    let no_bg = {strict_supported: some([pipelines])
+                 with default_options()};
+    assert parse_with(ts, all) == parse(ts);
+    assert parse_with(ts, no_bg)
+        == error("Background jobs are not yet supported.", none);
+    assert parse_with(tokenizer::tokenize("(a) > f"), no_bg)
+        == error("Subshells are not yet supported.", none);
+    assert parse_with(tokenizer::tokenize("a b"),
+                      {strict_supported: some([]) with default_options()})
+        == parse(tokenizer::tokenize("a b"));
+
+    fn spans(s: str) -> [(token, tokenizer::span)] {
+        ret tokenizer::tokenize_spans(tokenizer::default_config(), s);
+    }
+    assert parse_spans_with(spans("a | b &"), all) == parse(ts);
+    assert parse_spans_with(spans("a | b &"), no_bg)
+        == error("Background jobs are not yet supported.", some(6u));
+    assert parse_spans_with(spans("a; (b) > f"), no_bg)
+        == error("Subshells are not yet supported.", some(3u));
+    assert parse_spans_with(spans("é | b > f"),
+                            {strict_supported: some([pipelines])
+                             with default_options()})
+        == error("Redirections are not yet supported.", some(6u));
+}
+
+#[test]
+fn test_strict_expansions() {
+    fn check(s: str, f: feature, message: str) {
+        let ts = tokenizer::tokenize(s);
+        assert parse_with(ts, {strict_supported: some([])
+                               with default_options()})
+            == error(message + " are not yet supported.", none);
+        assert parse_with(ts, {strict_supported: some([f])
+                               with default_options()})
+            == parse(ts);
+    }
+    check("echo $x", variable_expansions, "Variable expansions");
+    check("echo ${x:-y}", parameter_expansions, "Parameter expansions");
+    check("echo $(date)", command_substitutions, "Command substitutions");
+    check("echo $((1 + 2))", arithmetic_expansions, "Arithmetic expansions");
+    check("ls ~/src", tilde_expansions, "Tilde expansions");
+    check("ls *.txt", globs, "Globs");
+    check("echo {a,b}", brace_expansions, "Brace expansions");
+    check("echo \"a $x\"", variable_expansions, "Variable expansions");
+
+    let none_ok = {strict_supported: some([]) with default_options()};
+    let redirects = {strict_supported: some([redirections])
+                     with default_options()};
+    assert parse_with(tokenizer::tokenize("cat <$f"), redirects)
+        == error("Variable expansions are not yet supported.", none);
+    assert parse_with(tokenizer::tokenize("cat <$f"), none_ok)
+        == error("Redirections are not yet supported.", none);
+    assert parse_with(tokenizer::tokenize("echo ${x:-$(date)}"),
+                      {strict_supported: some([parameter_expansions])
+                       with default_options()})
+        == error("Command substitutions are not yet supported.", none);
+    assert parse_with(tokenizer::tokenize("echo {$a,b}"),
+                      {strict_supported: some([brace_expansions])
+                       with default_options()})
+        == error("Variable expansions are not yet supported.", none);
+    assert parse_with(tokenizer::tokenize("echo '$x' \\* \"~\""), none_ok)
+        == parse(tokenizer::tokenize("echo '$x' \\* \"~\""));
+}
+
+#[test]
+fn test_lint_redirected_pipe() {
+    alt parse(tokenizer::tokenize("a >f | b")) {
+      parsed(cl) {
+        assert lint(cl)
+            == [{stage: 0u,
+                 message: "Stage 0 writes its output to 'f', so stage 1"
+                          + " reads nothing."}];
+      }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("a 2>f | b > g")) {
+      parsed(cl) { assert lint(cl) == []; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("x && (a | b >f | c)")) {
+      parsed(cl) { assert vec::len(lint(cl)) == 1u; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("a 2>&1 >f | b")) {
+      parsed(cl) { assert lint(cl) == []; }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("a >f 2>&1 | b")) {
+      parsed(cl) { assert vec::len(lint(cl)) == 1u; }
+      _ { assert false; }
+    }
+    let dup = {output: outfile(literal("f")),
+               fds: [{fd: 2u, target: fd_dup(1u)}]
+               with default_command(["a"])};
+    assert lint(pipeline([singleton(dup),
+                          singleton(default_command(["b"]))])) == [];
+}
+
+#[test]
+fn test_and_or_steps() {
+    fn simple(name: str) -> command_line {
+        ret singleton(default_command([name]));
+    }
+    alt parse(tokenizer::tokenize("a && b || c; d")) {
+      parsed(cl) {
+        assert and_or_steps(cl)
+            == [{connector: begin, cl: simple("a")},
+                {connector: on_success, cl: simple("b")},
+                {connector: on_failure, cl: simple("c")},
+                {connector: unconditional, cl: simple("d")}];
+      }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("a && (b || c)")) {
+      parsed(cl) {
+        assert and_or_steps(cl)
+            == [{connector: begin, cl: simple("a")},
+                {connector: on_success,
+                 cl: or([simple("b"), simple("c")])}];
+      }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("a | b")) {
+      parsed(cl) {
+        assert and_or_steps(cl) == [{connector: begin, cl: cl}];
+      }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_continue_tokens() {
+    let pending = tokenizer::tokenize("\\");
+    assert parse(pending) == continuation_required("");
+    assert parse(continue_tokens(pending, tokenizer::tokenize("")))
+        == empty;
+    assert parse(continue_tokens(pending, tokenizer::tokenize("echo hi")))
+        == parsed(singleton({args: [literal("echo"), literal("hi")],
+                             input: stdin,
+                             output: stdout,
+                             error: stderr,
+                             fds: [],
+                             closed: [],
+                             files: [],
+                             substitutions: []}));
+
+    let pending_and = tokenizer::tokenize("foo && \\");
+    assert parse(continue_tokens(pending_and, tokenizer::tokenize("bar")))
+        == parse(tokenizer::tokenize("foo && bar"));
+    assert parse(continue_tokens(pending_and, tokenizer::tokenize("")))
+        == error("Missing command at end of line.", none);
+}
+
+#[test]
+fn test_long_pipeline() {
+    let line = "cmd0";
+    let i = 1u;
+    while i < 1000u {
+        line += " | cmd" + uint::to_str(i, 10u);
+        i += 1u;
+    }
+    alt parse(tokenizer::tokenize(line)) {
+      parsed(pipeline(stages)) {
+        assert vec::len(stages) == 1000u;
+        assert stages[0u] == singleton({args: [literal("cmd0")],
+                                        input: stdin,
+                                        output: stdout,
+                                        error: stderr,
+                                        fds: [],
+                                        closed: [],
+                                        files: [],
+                                        substitutions: []});
+        assert stages[999u] == singleton({args: [literal("cmd999")],
+                                          input: stdin,
+                                          output: stdout,
+                                          error: stderr,
+                                          fds: [],
+                                          closed: [],
+                                          files: [],
+                                          substitutions: []});
+      }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_empty() {
+    assert parse([]) == empty;
+    assert parse(tokenizer::tokenize("")) == empty;
+    assert parse(tokenizer::tokenize(" \t ")) == empty;
+    assert parse(tokenizer::tokenize(tokenizer::strip_comments("  # hi")))
+        == empty;
+    assert parse(tokenizer::tokenize("true")) != empty;
+}
+
+#[test]
+fn test_trailing_pipe() {
+    let pending = tokenizer::tokenize("ls |");
+    assert parse(pending) == continuation_required("pipe>");
+    assert parse(continue_tokens(pending, tokenizer::tokenize("wc")))
+        == parse(tokenizer::tokenize("ls | wc"));
+    assert parse(tokenizer::tokenize("ls | \\")) == continuation_required("");
+    alt parse(tokenizer::tokenize("ls || ")) {
+      error(_, _) { }
+      _ { assert false; }
+    }
+    alt parse(tokenizer::tokenize("ls &&")) {
+      error(_, _) { }
+      _ { assert false; }
+    }
+    let both = tokenizer::tokenize("ls |&");
+    assert parse(both) == continuation_required("pipe>");
+    assert parse(continue_tokens(both, tokenizer::tokenize("wc")))
+        == parse(tokenizer::tokenize("ls |& wc"));
+}
+
+#[test]
+fn test_default_command() {
+    assert default_command(["ls", "-l"]) == {args: [literal("ls"),
+                                                    literal("-l")],
+                                             input: stdin,
+                                             output: stdout,
+                                             error: stderr,
+                                             fds: [],
+                                             closed: [],
+                                             files: [],
+                                             substitutions: []};
+    assert parse(tokenizer::tokenize("sort < in | uniq 2>&1 > out"))
+        == parsed(pipeline(
+            [singleton({input: infile(literal("in")), files: ["in"]
+                        with default_command(["sort"])}),
+             singleton({output: outfile(literal("out")), error: stdout,
+                        files: ["out"] with default_command(["uniq"])})]));
+}
+
+#[test]
+fn test_redirect_after_operator() {
+    let msg = "Found a redirection where a command was expected.";
+    assert parse(tokenizer::tokenize("a | >b")) == error(msg, none);
+    assert parse(tokenizer::tokenize("a && 2>b")) == error(msg, none);
+    assert parse(tokenizer::tokenize("a || <b c")) == parse(
+        tokenizer::tokenize("a || c <b"));
+    assert parse(tokenizer::tokenize("a; >b"))
+        == parsed(sequence([singleton(default_command(["a"])),
+                            singleton({output: outfile(literal("b")),
+                                       files: ["b"]
+                                       with default_command([])})]));
+}
+
+#[test]
+fn test_limits() {
+    let ts = tokenizer::tokenize("a | (b | (c; d | e | f))");
+    assert parse_with(ts, {max_tokens: some(9u) with default_options()})
+        == error("Too many tokens (limit 9).", none);
+    assert parse_with(ts, {max_nesting: some(1u) with default_options()})
+        == error("Subshells nested too deeply (limit 1).", none);
+    assert parse_with(ts, {max_pipeline_width: some(2u)
+                           with default_options()})
+        == error("Pipeline has too many stages (limit 2).", none);
+    assert parse_with(ts, {max_tokens: some(15u),
+                           max_nesting: some(2u),
+                           max_pipeline_width: some(3u)
+                           with default_options()})
+        == parse(ts);
+
+    let doc = tokenizer::tokenize("cat <<A; cat <<B\nabc\nA\nlonger\nB\n");
+    assert parse_with(doc, {max_here_doc_bytes: some(6u)
+                            with default_options()})
+        == error("Here-doc body too large (limit 6).", none);
+    assert parse_with(doc, {max_here_doc_bytes: some(7u)
+                            with default_options()})
+        == parse(doc);
+}
+
+#[test]
+fn test_node_count_and_depth() {
+    fn parsed_cl(cmd_line: str) -> command_line {
+        ret alt parse(tokenizer::tokenize(cmd_line)) {
+          parsed(cl) { cl }
+          _ { fail("Expected " + cmd_line + " to parse."); }
+        };
+    }
+    let ls = parsed_cl("ls");
+    assert node_count(ls) == 1u;
+    assert depth(ls) == 1u;
+    let flat = parsed_cl("a; b; c; d");
+    assert node_count(flat) == 5u;
+    assert depth(flat) == 2u;
+    let nested = parsed_cl("a && (b | (c; (d || e)))");
+    assert node_count(nested) == 9u;
+    assert depth(nested) == 5u;
+    let bg = parsed_cl("a | b &");
+    assert node_count(bg) == 4u;
+    assert depth(bg) == 3u;
+    let negated = parsed_cl("! a | b");
+    assert node_count(negated) == 4u;
+    assert depth(negated) == 3u;
+}
+
+#[test]
+fn test_subshell_pipeline() {
+    fn simple(name: str) -> command_line {
+        ret singleton(default_command([name]));
+    }
+    assert parse(tokenizer::tokenize("(a; b) | (c; d)"))
+        == parsed(pipeline([sequence([simple("a"), simple("b")]),
+                            sequence([simple("c"), simple("d")])]));
+    assert parse(tokenizer::tokenize("(a; b) | c"))
+        == parsed(pipeline([sequence([simple("a"), simple("b")]),
+                            simple("c")]));
+    assert parse(tokenizer::tokenize("a | (b && c) | d"))
+        == parsed(pipeline([simple("a"), and([simple("b"), simple("c")]),
+                            simple("d")]));
+}
+
+#[test]
+fn test_into_result() {
+    let ls = singleton(default_command(["ls"]));
+    assert into_result(parsed(ls)) == ok(ls);
+    assert into_result(empty) == ok(sequence([]));
+    assert into_result(continuation_required(""))
+        == err("Continuation required.");
+    assert into_result(error("Unexpected ')'.", none))
+        == err("Unexpected ')'.");
+
+    assert into_result_strict(parsed(ls)) == ok(ls);
+    assert into_result_strict(empty) == err("Empty command line.");
+    assert into_result_strict(continuation_required(""))
+        == err("Unexpected end of input.");
+    assert into_result_strict(parse(tokenizer::tokenize(")")))
+        == err("Unexpected ')'.");
+}
+
+#[test]
+fn test_error_offset() {
+    assert parse(tokenizer::tokenize("ls | grep 'a b"))
+        == error("Missing '.", some(10u));
+    assert parse(tokenizer::tokenize("(echo $(date"))
+        == error("Missing ).", some(6u));
+    assert parse(tokenizer::tokenize("ls )")) == error("Unexpected ')'.", none);
+}
+
+#[test]
+fn test_append_redirect() {
+    assert parse(tokenizer::tokenize("make >> build.log 2>&1"))
+        == parsed(singleton({output: appendfile(literal("build.log")),
+                             error: appendfile(literal("build.log")),
+                             files: ["build.log"]
+                             with default_command(["make"])}));
+    assert parse(tokenizer::tokenize("a > x >> y"))
+        == parsed(singleton({output: appendfile(literal("y")), files: ["x", "y"]
+                             with default_command(["a"])}));
+    alt parse(tokenizer::tokenize("a >> log | b")) {
+      parsed(cl) {
+        assert referenced_files(cl) == ["log"];
+        assert vec::len(lint(cl)) == 1u;
+      }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_error_append_redirect() {
+    assert parse(tokenizer::tokenize("make 2>> errors.log > out"))
+        == parsed(singleton({output: outfile(literal("out")),
+                             error: appendfile(literal("errors.log")),
+                             files: ["errors.log", "out"]
+                             with default_command(["make"])}));
+}
+
+#[test]
+fn test_redirect_both() {
+    assert make_command(tokenizer::tokenize("make &> build.log"))
+        == left({output: outfile(literal("build.log")),
+                 error: outfile(literal("build.log")),
+                 files: ["build.log"] with default_command(["make"])});
+    assert make_command(tokenizer::tokenize("make > out &> build.log"))
+        == left({output: outfile(literal("build.log")),
+                 error: outfile(literal("build.log")),
+                 files: ["out", "build.log"] with default_command(["make"])});
+    assert make_command(tokenizer::tokenize("make 2> err &> build.log"))
+        == right("Multiple error redirects.");
+    assert parse(tokenizer::tokenize("make & > build.log"))
+        == parsed(sequence([background(@singleton(default_command(["make"]))),
+                            singleton({output: outfile(literal("build.log")),
+                                       files: ["build.log"]
+                                       with default_command([])})]));
+    alt parse(tokenizer::tokenize("make &> build.log")) {
+      parsed(cl) { assert referenced_files(cl) == ["build.log"]; }
+      _ { assert false; }
+    }
+    assert make_command(tokenizer::tokenize("make &>> build.log"))
+        == left({output: appendfile(literal("build.log")),
+                 error: appendfile(literal("build.log")), files: ["build.log"]
+                 with default_command(["make"])});
+    assert make_command(tokenizer::tokenize("make &>>a &>b"))
+        == right("Multiple error redirects.");
+}
+
+#[test]
+fn test_background_and_or_list() {
+    let a = singleton(default_command(["a"]));
+    let b = singleton(default_command(["b"]));
+    assert parse(tokenizer::tokenize("a && b &"))
+        == parsed(background(@and([a, b])));
+    assert parse(tokenizer::tokenize("a || b &"))
+        == parsed(background(@or([a, b])));
+    assert parse(tokenizer::tokenize("a && b & a"))
+        == parsed(sequence([background(@and([a, b])), a]));
+}
+
+#[test]
+fn test_numbered_fds() {
+    assert make_command(tokenizer::tokenize("exec 3>socket 5< input"))
+        == left({fds: [{fd: 3u, target: fd_outfile(literal("socket"))},
+                       {fd: 5u, target: fd_infile(literal("input"))}],
+                 files: ["socket", "input"]
+                 with default_command(["exec"])});
+    assert make_command(tokenizer::tokenize("cmd 3>a 3<b"))
+        == right("Multiple redirects of descriptor 3.");
+    assert make_command(tokenizer::tokenize("cmd 1>a 2>b"))
+        == left({output: outfile(literal("a")), error: outfile(literal("b")),
+                 files: ["a", "b"] with default_command(["cmd"])});
+    alt parse(tokenizer::tokenize("cmd <a 4>b >c")) {
+      parsed(cl) { assert referenced_files(cl) == ["a", "b", "c"]; }
+      _ { assert false; }
+    }
+}
+
+#[test]
+fn test_referenced_variables() {
+    fn variables(s: str) -> [str] {
+        ret alt parse(tokenizer::tokenize(s)) {
+          parsed(cl) { referenced_variables(cl) }
+          _ { fail("Expected a command line."); }
+        };
+    }
+    assert variables("echo $A ${B} $(echo $C)") == ["A", "B", "C"];
+    assert variables("A=1 B=$C cmd >$D 2>\"$E\" <<<$F 3<$G")
+        == ["C", "F", "D", "E", "G"];
+    assert variables("echo ${X:-$Y} '$Z' \\$V \"$U\"/bin $(cat <$W)")
+        == ["X", "Y", "U", "W"];
+    assert variables("(echo $A; a$B{$C,d}$E) | b $A")
+        == ["A", "B", "C", "E", "A"];
+    assert variables("echo $(echo $(echo $N))") == ["N"];
+    assert variables("ls -l") == [];
+}
+
+#[test]
+fn test_parse_substitutions() {
+    let opts = {parse_substitutions: true with default_options()};
+    fn subs(s: str, opts: parse_options) -> [command_line] {
+        ret alt parse_with(tokenizer::tokenize(s), opts) {
+          parsed(singleton(c)) { c.substitutions }
+          _ { fail("Expected a single command."); }
+        };
+    }
+    let a = singleton(default_command(["a"]));
+    let b = singleton(default_command(["b"]));
+    assert subs("echo $(a | b)", opts) == [pipeline([a, b])];
+    assert subs("echo $(a | b)", default_options()) == [];
+    assert parse_with(tokenizer::tokenize("echo $(a | b)"), opts)
+        == parsed(singleton({args: [literal("echo"),
+                                    tokenizer::command_subst("a | b")],
+                             substitutions: [pipeline([a, b])]
+                             with default_command([])}));
+    assert subs("echo $(a) \"$(b)\" '$(c)'", opts) == [a, b];
+    assert subs("cat >$(b) <$(a)", opts) == [a, b];
+    assert subs("echo $(echo $(a))", opts)
+        == [singleton({args: [literal("echo"),
+                              tokenizer::command_subst("a")],
+                       substitutions: [a] with default_command([])})];
+    assert subs("echo $()", opts) == [];
+
+    let shallow = {max_nesting: some(1u) with opts};
+    assert subs("echo $(a)", shallow) == [a];
+    assert parse_with(tokenizer::tokenize("echo $(echo $(a))"), shallow)
+        == error("Command substitutions nested too deeply (limit 1).", none);
+    assert parse_with(tokenizer::tokenize("(echo $(a))"), shallow)
+        == error("Command substitutions nested too deeply (limit 1).", none);
+    assert parse_with(tokenizer::tokenize("echo $( (a) )"), shallow)
+        == error("Subshells nested too deeply (limit 1).", none);
+    assert subs("echo $( (a) )", {max_nesting: some(2u) with opts})
+        == [a];
+    assert parse_with(tokenizer::tokenize("echo $(a |)"), opts)
+        == error("Incomplete command substitution.", none);
+    assert parse_with(tokenizer::tokenize("echo $(a && && b)"), opts)
+        == parse(tokenizer::tokenize("a && && b"));
+}
+
+#[test]
+fn test_fd_here_docs() {
+    let h = {delimiter: "EOF", body: "hello\n", quoted: false,
+             strip_tabs: false};
+    assert make_command(tokenizer::tokenize("cat 3<<EOF\nhello\nEOF"))
+        == left({fds: [{fd: 3u, target: fd_here_doc_input(h)}]
+                 with default_command(["cat"])});
+    assert make_command(tokenizer::tokenize("cmd 4<<<word"))
+        == left({fds: [{fd: 4u, target: fd_herestring(literal("word"))}]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd <<<a 4<<<b"))
+        == left({input: herestring(literal("a")),
+                 fds: [{fd: 4u, target: fd_herestring(literal("b"))}]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd 4<in 4<<<b"))
+        == right("Multiple redirects of descriptor 4.");
+    alt parse(tokenizer::tokenize("cat 3<<EOF | wc\nhello\nEOF")) {
+      parsed(cl) {
+        assert command_line_to_string(cl) == "cat 3<<EOF | wc\nhello\nEOF";
+      }
       _ { assert false; }
     }
 }
+
+#[test]
+fn test_tee_output() {
+    let cl = alt parse(tokenizer::tokenize("make >out 2>>err | grep x")) {
+      parsed(cl) { cl }
+      _ { fail("Expected the pipeline to parse."); }
+    };
+    let make = {output: teed(@outfile(literal("out")), "log"),
+                error: teed(@appendfile(literal("err")), "log"),
+                files: ["out", "err"]
+                with default_command(["make"])};
+    let grep = {output: teed(@stdout, "log"), error: teed(@stderr, "log")
+                with default_command(["grep", "x"])};
+    let teed_cl = tee_output(cl, "log");
+    assert teed_cl == pipeline([singleton(make), singleton(grep)]);
+    assert tee_output(teed_cl, "log") == teed_cl;
+    assert referenced_files(teed_cl) == referenced_files(cl);
+}
+
+#[test]
+fn test_dup_fd() {
+    assert make_command(tokenizer::tokenize("echo oops 1>&2"))
+        == left({output: stderr with default_command(["echo", "oops"])});
+    assert make_command(tokenizer::tokenize("make 2>log 1>&2"))
+        == left({output: outfile(literal("log")),
+                 error: outfile(literal("log")),
+                 files: ["log"] with default_command(["make"])});
+    assert make_command(tokenizer::tokenize("make 2>&3"))
+        == left({fds: [{fd: 2u, target: fd_dup(3u)}]
+                 with default_command(["make"])});
+    assert make_command(tokenizer::tokenize("make 1>&1"))
+        == left(default_command(["make"]));
+    assert make_command(tokenizer::tokenize("exec 3>&2 4>&3"))
+        == left({fds: [{fd: 3u, target: fd_dup(2u)},
+                       {fd: 4u, target: fd_dup(3u)}]
+                 with default_command(["exec"])});
+    assert make_command(tokenizer::tokenize("cmd 1>&3"))
+        == left({fds: [{fd: 1u, target: fd_dup(3u)}]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd >&3 2>&1"))
+        == left({fds: [{fd: 1u, target: fd_dup(3u)},
+                       {fd: 2u, target: fd_dup(3u)}]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd 2>&4 >&2"))
+        == left({fds: [{fd: 2u, target: fd_dup(4u)},
+                       {fd: 1u, target: fd_dup(4u)}]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd >&3 >out"))
+        == left({output: outfile(literal("out")), files: ["out"]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd 2>&3 2>e"))
+        == right("Multiple error redirects.");
+    assert make_command(tokenizer::tokenize("cmd 0>&3"))
+        == right("Cannot duplicate descriptor 3 onto descriptor 0.");
+    assert make_command(tokenizer::tokenize("cmd >out >&2"))
+        == left({output: stderr, files: ["out"]
+                 with default_command(["cmd"])});
+}
+
+#[test]
+fn test_close_fd() {
+    assert make_command(tokenizer::tokenize("cmd 1>&-"))
+        == left({closed: [1u] with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("ls 2>&- >out"))
+        == left({output: outfile(literal("out")), closed: [2u], files: ["out"]
+                 with default_command(["ls"])});
+    assert make_command(tokenizer::tokenize("ls 2>&- 2>err"))
+        == right("Descriptor 2 is both closed and redirected.");
+    assert make_command(tokenizer::tokenize("ls 3>x 3>&-"))
+        == right("Descriptor 3 is both closed and redirected.");
+    assert make_command(tokenizer::tokenize("ls 3>&- 3>&-"))
+        == right("Descriptor 3 is closed more than once.");
+}
+
+#[test]
+fn test_close_fd_before_copy() {
+    assert make_command(tokenizer::tokenize("cmd 3>&- 4>&3"))
+        == right("Descriptor 3 is closed.");
+    assert make_command(tokenizer::tokenize("cmd >&- 2>&1"))
+        == right("Descriptor 1 is closed.");
+    assert make_command(tokenizer::tokenize("exec 4>&3 3>&-"))
+        == left({fds: [{fd: 4u, target: fd_dup(3u)}], closed: [3u]
+                 with default_command(["exec"])});
+    assert make_command(tokenizer::tokenize("cmd 3>&1 >f"))
+        == right("Descriptor 1 is redirected after being copied.");
+    assert make_command(tokenizer::tokenize("cmd 3>&2 2>&1"))
+        == right("Descriptor 2 is redirected after being copied.");
+}
+
+#[test]
+fn test_normalize_redirections() {
+    let open = [0u, 1u, 2u];
+    let f = literal("f");
+    fn ops(s: str, open: [uint]) -> result<[fd_op], str> {
+        alt make_command(tokenizer::tokenize(s)) {
+          left(c) { normalize_redirections(c, open) }
+          right(e) { fail e; }
+        }
+    }
+    assert ops("cmd", open) == ok([]);
+    assert ops("cmd >a >b", open) == ok([open_write(1u, literal("b"))]);
+    assert ops("cmd 2>&1 >f", open) == ok([copy_fd(2u, 1u), open_write(1u, f)]);
+    assert ops("cmd >f 2>&1", open) == ok([open_write(1u, f), copy_fd(2u, 1u)]);
+    assert ops("cmd <f >>f", open)
+        == ok([open_read(0u, f), open_append(1u, f)]);
+    assert ops("cmd >&2", open) == ok([copy_fd(1u, 2u)]);
+    assert ops("cmd 4>&3", open) == err("Descriptor 3 is not open.");
+    assert ops("cmd 4>&3", [0u, 1u, 2u, 3u]) == ok([copy_fd(4u, 3u)]);
+    assert ops("cmd 3>f 4>&3", open)
+        == ok([open_write(3u, f), copy_fd(4u, 3u)]);
+    assert ops("exec 4>&3 3>&-", [0u, 1u, 2u, 3u])
+        == ok([copy_fd(4u, 3u), close(3u)]);
+    assert ops("cmd 2>&- >&3", [0u, 1u, 2u, 3u])
+        == ok([copy_fd(1u, 3u), close(2u)]);
+    let cl = tee_output(singleton(default_command(["make"])), "log");
+    alt cl {
+      singleton(c) { assert normalize_redirections(c, open) == ok([]); }
+      _ { fail; }
+    }
+}
+
+#[test]
+fn test_here_doc() {
+    let doc = {delimiter: "EOF", body: "hello\n", quoted: false,
+               strip_tabs: false};
+    assert parse(tokenizer::tokenize("cat <<EOF | wc\nhello\nEOF"))
+        == parsed(pipeline([singleton({input: here_doc_input(doc)
+                                       with default_command(["cat"])}),
+                            singleton(default_command(["wc"]))]));
+    assert parse(tokenizer::tokenize("cat <<EOF; echo after\nhello\nworld\n"
+                                     + "EOF"))
+        == parsed(sequence([singleton({input: here_doc_input(
+                                           {body: "hello\nworld\n" with doc})
+                                       with default_command(["cat"])}),
+                            singleton(default_command(["echo", "after"]))]));
+    assert parse(tokenizer::tokenize("cat <<EOF\nhello"))
+        == continuation_required("heredoc>");
+    assert parse(tokenizer::tokenize("cat <in <<EOF\nEOF"))
+        == error("Multiple input redirects.", none);
+    assert referenced_files(singleton({input: here_doc_input(doc)
+                                       with default_command(["cat"])}))
+        == [];
+}
+
+#[test]
+fn test_last_output_wins() {
+    assert make_command(tokenizer::tokenize("cmd > a >> b"))
+        == left({output: appendfile(literal("b")), files: ["a", "b"]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd >> a > b"))
+        == left({output: outfile(literal("b")), files: ["a", "b"]
+                 with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd > a 2>&1 > b"))
+        == left({output: outfile(literal("b")), error: outfile(literal("a")),
+                 files: ["a", "b"] with default_command(["cmd"])});
+    assert make_command(tokenizer::tokenize("cmd 2> a 2> b"))
+        == right("Multiple error redirects.");
+}
+
+#[test]
+fn test_here_string() {
+    assert make_command(tokenizer::tokenize("grep foo <<< \"$bar baz\""))
+        == left({input: herestring(tokenizer::word([tokenizer::variable("bar"),
+                                                    literal(" baz")]))
+                 with default_command(["grep", "foo"])});
+    assert make_command(tokenizer::tokenize("cat <in <<< x"))
+        == right("Multiple input redirects.");
+}
+
+#[test]
+fn test_map_args() {
+    fn no_color(args: [token]) -> [token] {
+        if vec::is_empty(args) {
+            ret args;
+        }
+        ret [args[0u], literal("--color=never")] + vec::slice(args, 1u,
+                                                              vec::len(args));
+    }
+    let cl = alt parse(tokenizer::tokenize("ls -l 2>err | grep x > out")) {
+      parsed(cl) { cl }
+      _ { fail("Expected the pipeline to parse."); }
+    };
+    let colorless = map_args(cl, no_color);
+    assert colorless
+        == pipeline([singleton({error: outfile(literal("err")), files: ["err"]
+                                with default_command(["ls", "--color=never",
+                                                      "-l"])}),
+                     singleton({output: outfile(literal("out")), files: ["out"]
+                                with default_command(["grep",
+                                                      "--color=never",
+                                                      "x"])})]);
+    assert map_args(cl, {|args| args}) == cl;
+    assert referenced_files(colorless) == referenced_files(cl);
+    assert with_args(default_command(["a"]), [literal("b"), literal("c")])
+        == default_command(["b", "c"]);
+}
+
+#[test]
+fn test_comment_only() {
+    assert parse(tokenizer::tokenize("# nothing to see")) == empty;
+    assert parse(tokenizer::tokenize("ls # list")) == parse(
+        tokenizer::tokenize("ls"));
+}
+
+#[test]
+fn test_multiline() {
+    assert parse(tokenizer::tokenize("echo a\necho b\n"))
+        == parsed(sequence([singleton(default_command(["echo", "a"])),
+                            singleton(default_command(["echo", "b"]))]));
+    assert parse(tokenizer::tokenize("a |\nb")) == parse(
+        tokenizer::tokenize("a | b"));
+    assert parse(tokenizer::tokenize("\n\n")) == empty;
+}
+
+#[test]
+fn test_pipe_both() {
+    assert parse(tokenizer::tokenize("make |& less"))
+        == parsed(pipeline([singleton({error: stdout
+                                       with default_command(["make"])}),
+                            singleton(default_command(["less"]))]));
+    assert parse(tokenizer::tokenize("make 2>err |& less"))
+        == parse(tokenizer::tokenize("make 2>&1 | less"));
+    assert parse(tokenizer::tokenize("a | b |& c"))
+        == parsed(pipeline([singleton(default_command(["a"])),
+                            singleton({error: stdout
+                                       with default_command(["b"])}),
+                            singleton(default_command(["c"]))]));
+    assert parse(tokenizer::tokenize("a |&")) == continuation_required("pipe>");
+    assert parse(tokenizer::tokenize("(a) |& b"))
+        == parsed(pipeline([singleton({error: stdout
+                                       with default_command(["a"])}),
+                            singleton(default_command(["b"]))]));
+    assert parse(tokenizer::tokenize("(a 2>e; b >o 3>&2) |& c"))
+        == parsed(pipeline([sequence([singleton({error: outfile(literal("e")),
+                                                 files: ["e"]
+                                                 with default_command(
+                                                     ["a"])}),
+                                      singleton({output: outfile(literal("o")),
+                                                 error: stdout,
+                                                 fds: [{fd: 3u,
+                                                        target: fd_dup(1u)}],
+                                                 files: ["o"]
+                                                 with default_command(
+                                                     ["b"])})]),
+                            singleton(default_command(["c"]))]));
+    assert parse(tokenizer::tokenize("a >&3 |& b"))
+        == parsed(pipeline([singleton({fds: [{fd: 1u, target: fd_dup(3u)},
+                                             {fd: 2u, target: fd_dup(3u)}]
+                                       with default_command(["a"])}),
+                            singleton(default_command(["b"]))]));
+    assert parse(tokenizer::tokenize("a >&3 |& b"))
+        == parse(tokenizer::tokenize("a >&3 2>&1 | b"));
+    assert parse(tokenizer::tokenize("a 2>&4 |& b"))
+        == parse(tokenizer::tokenize("a 2>&1 | b"));
+}
+
+#[test]
+fn test_explain() {
+    assert explain("grep -i foo < in.txt | wc -l > count &")
+        == "(run `grep` with arguments `-i` `foo`, reading input from"
+           + " `in.txt`, piping its output into `wc` with argument `-l`,"
+           + " writing output to `count`), in the background";
+    assert explain("make 2>> err.log && make install || echo failed")
+        == "(run `make`, appending errors to `err.log`; if that succeeds,"
+           + " run `make` with argument `install`); if that fails, run"
+           + " `echo` with argument `failed`";
+    assert explain("cd /tmp; ls >out 2>&1")
+        == "run `cd` with argument `/tmp`; then run `ls`, writing output"
+           + " to `out`, sending errors along with its output";
+    assert explain("exec 3<in 4>&-")
+        == "run `exec`, reading descriptor 3 from `in`, closing descriptor"
+           + " 4";
+    assert explain("ls |") == "an incomplete command line";
+    assert explain("") == "nothing";
+    assert explain("ls )") == "a command line that does not parse:"
+                              + " Unexpected ')'.";
+}
+
+#[test]
+fn test_negate() {
+    let a = singleton(default_command(["a"]));
+    let b = singleton(default_command(["b"]));
+    let c = singleton(default_command(["c"]));
+    assert parse(tokenizer::tokenize("! a")) == parsed(negate(@a));
+    assert parse(tokenizer::tokenize("! a | b && c"))
+        == parsed(and([negate(@pipeline([a, b])), c]));
+    assert parse(tokenizer::tokenize("a && b | c"))
+        == parsed(and([a, pipeline([b, c])]));
+    assert parse(tokenizer::tokenize("a; ! b"))
+        == parsed(sequence([a, negate(@b)]));
+    assert parse(tokenizer::tokenize("echo a!b"))
+        == parsed(singleton(default_command(["echo", "a!b"])));
+    assert parse(tokenizer::tokenize("a | ! b"))
+        == error("Unexpected '!'.", none);
+    assert parse(tokenizer::tokenize("! > f"))
+        == error("Found a redirection where a command was expected.", none);
+    assert parse(tokenizer::tokenize("!"))
+        == error("Missing command at end of line.", none);
+    assert explain("! grep -q x f")
+        == "run `grep` with arguments `-q` `x` `f`, inverting its exit"
+           + " status";
+}
+
+#[test]
+fn test_precedence() {
+    let a = singleton(default_command(["a"]));
+    let b = singleton(default_command(["b"]));
+    let c = singleton(default_command(["c"]));
+    let d = singleton(default_command(["d"]));
+    assert parse(tokenizer::tokenize("a | b || c"))
+        == parsed(or([pipeline([a, b]), c]));
+    assert parse(tokenizer::tokenize("a || b | c && d"))
+        == parsed(and([or([a, pipeline([b, c])]), d]));
+    assert parse(tokenizer::tokenize("a; b && c"))
+        == parsed(sequence([a, and([b, c])]));
+    assert parse(tokenizer::tokenize("a && b; c | d &"))
+        == parsed(sequence([and([a, b]), background(@pipeline([c, d]))]));
+}
+
+#[test]
+fn test_trailing_separator() {
+    let a = singleton(default_command(["a"]));
+    let b = singleton(default_command(["b"]));
+    assert parse(tokenizer::tokenize("a;")) == parsed(sequence([a]));
+    assert parse(tokenizer::tokenize("a; b;")) == parsed(sequence([a, b]));
+    assert parse(tokenizer::tokenize("a &")) == parsed(background(@a));
+    let msg = "Found a separator where a command was expected.";
+    assert parse(tokenizer::tokenize("a; ;")) == error(msg, none);
+    assert parse(tokenizer::tokenize("a &; b")) == error(msg, none);
+}
+
+#[test]
+fn test_dependency_graph() {
+    fn graph_of(s: str) -> [[uint]] {
+        let after: [[uint]] = [];
+        alt parse(tokenizer::tokenize(s)) {
+          parsed(cl) {
+            for n in dependency_graph(cl) {
+                after += [n.after];
+            }
+          }
+          _ { fail; }
+        }
+        ret after;
+    }
+    assert graph_of("cc -c a.c > a.log; cc -c b.c > b.log") == [[], []];
+    assert graph_of("echo one > out; echo two >> out") == [[], [0u]];
+    assert graph_of("sort in > a; wc -l a; ls > b; cat a b")
+        == [[], [0u], [], [0u, 1u, 2u]];
+    assert graph_of("ls -l") == [[]];
+    assert graph_of("make -C x & make -C y") == [[], []];
+}
+
+#[test]
+fn test_variables() {
+    assert parse(tokenizer::tokenize("echo $HOME \"$HOME/bin\" > $LOG"))
+        == parsed(singleton({args: [literal("echo"),
+                                    tokenizer::variable("HOME"),
+                                    tokenizer::word([
+                                        tokenizer::variable("HOME"),
+                                        literal("/bin")])],
+                             output: outfile(tokenizer::variable("LOG")),
+                             files: ["$LOG"]
+                             with default_command([])}));
+    assert parse(tokenizer::tokenize("echo ${X:-'a b'}"))
+        == parsed(singleton({args: [literal("echo"),
+                                    tokenizer::parameter(
+                                        "X", tokenizer::use_default,
+                                        @literal("a b"))]
+                             with default_command([])}));
+}
+
+#[test]
+fn test_quoted_words_stay_distinct() {
+    fn command_of(s: str) -> command {
+        ret alt make_command(tokenizer::tokenize(s)) {
+          left(c) { c }
+          right(e) { fail(e); }
+        };
+    }
+    assert command_of("echo '$HOME'").args
+        == [literal("echo"), literal("$HOME")];
+    assert command_of("echo $HOME").args
+        == [literal("echo"), tokenizer::variable("HOME")];
+    assert command_of("echo '$HOME'") != command_of("echo $HOME");
+    assert command_of("echo \\$HOME") != command_of("echo $HOME");
+    assert command_of("ls \"a*\"*").args
+        == [literal("ls"), tokenizer::glob("a\\**")];
+    assert command_of("ls 'a*'").args == [literal("ls"), literal("a*")];
+    assert command_of("cat >'$x'").output == outfile(literal("$x"));
+    assert command_of("cat >$x").output == outfile(tokenizer::variable("x"));
+    assert command_of("cat >'$x'").files == command_of("cat >$x").files;
+}
+
+#[test]
+fn test_statement_stream() {
+    fn statements_of(chunks: [str]) -> [command_line] {
+        let stream = new_statement_stream();
+        let cls: [command_line] = [];
+        for chunk in chunks {
+            for r in feed_statements(stream, chunk) {
+                alt r {
+                  parsed(cl) { cls += [cl]; }
+                  _ { fail; }
+                }
+            }
+        }
+        alt finish_statements(stream) {
+          parsed(cl) { cls += [cl]; }
+          empty { }
+          _ { fail; }
+        }
+        ret cls;
+    }
+    let a = singleton(default_command(["echo", "a"]));
+    let b = singleton(default_command(["echo", "b"]));
+    for chunks in [["echo a; echo b"], ["ec", "ho a", ";", " echo b"],
+                   ["echo a;", " echo b"], ["echo a", "; echo ", "b"],
+                   ["echo a\n", "\necho b\n"], ["echo a # x; y\n", "echo b"],
+                   ["echo \\", "\na; echo b"], ["echo a # \\", "\necho b"]] {
+        assert statements_of(chunks) == [a, b];
+    }
+    // Terminators inside quotes are part of the word.
+    assert statements_of(["echo 'x;\n", "y' &", "& echo b"])
+        == [and([singleton(default_command(["echo", "x;\ny"])), b])];
+
+    let stream = new_statement_stream();
+    assert feed_statements(stream, "echo a; ec") == [parsed(a)];
+    assert feed_statements(stream, "ho b &") == [];
+    assert feed_statements(stream, "\n") == [parsed(background(@b))];
+    assert finish_statements(stream) == empty;
+
+    assert statements_of(["make &", "> log"])
+        == [singleton({output: outfile(literal("log")),
+                       error: outfile(literal("log")),
+                       files: ["log"] with default_command(["make"])})];
+    assert statements_of(["echo a &", "& echo b"]) == [and([a, b])];
+    assert statements_of(["cat <<E\nx; y\n", "E\n", "echo b"])
+        == [singleton({input: here_doc_input({delimiter: "E",
+                                              body: "x; y\n",
+                                              quoted: false, strip_tabs: false})
+                       with default_command(["cat"])}), b];
+
+    stream = new_statement_stream();
+    assert feed_statements(stream, "echo a |\n") == [];
+    assert finish_statements(stream) == continuation_required("pipe>");
+
+    stream = new_statement_stream();
+    assert feed_statements(stream, "echo $((1 +\n") == [];
+    assert finish_statements(stream) == continuation_required("arith>");
+    assert feed_statements(stream, "2))\n")
+        == [parsed(singleton({args: [literal("echo"),
+                                     tokenizer::arith("1 +\n2")]
+                              with default_command([])}))];
+
+    // A statement that can never parse is reported and dropped.
+    stream = new_statement_stream();
+    assert feed_statements(stream, ")\n")
+        == [error("Unexpected ')'.", none)];
+    assert feed_statements(stream, "echo b\n") == [parsed(b)];
+    assert feed_statements(stream, "echo a ;; echo b\n")
+        == [parsed(a), error("No initial command.", none), parsed(b)];
+    assert feed_statements(stream, "wc <\necho b\n")
+        == [error("No input file specified.", some(30u)), parsed(b)];
+    assert finish_statements(stream) == empty;
+
+    // Tokenizer error offsets count from the start of the stream.
+    stream = new_statement_stream();
+    assert feed_statements(stream, "echo a\nwc <\n")
+        == [parsed(a), error("No input file specified.", some(11u))];
+    stream = new_statement_stream();
+    assert feed_statements(stream, "echo a\n") == [parsed(a)];
+    assert feed_statements(stream, "wc <\n")
+        == [error("No input file specified.", some(11u))];
+    assert feed_statements(stream, "echo b; wc <") == [parsed(b)];
+    assert finish_statements(stream)
+        == error("No input file specified.", some(24u));
+}
+
+#[test]
+fn test_command_line_to_string() {
+    fn render(s: str) -> str {
+        ret alt parse(tokenizer::tokenize(s)) {
+          parsed(cl) { command_line_to_string(cl) }
+          _ { fail("Expected a command line."); }
+        };
+    }
+    assert render("(cat abc d\"e f\\\"\"g; echo 'hello\\') |"
+                  + " grep -i he >matches &")
+        == "( cat abc 'de f\"g' ; echo 'hello\\' ) | grep -i he >matches &";
+    assert render("! a | b && c") == "! a | b && c";
+    assert render("a || (b && c)") == "a || ( b && c )";
+    assert render("(a || b) && c") == "a || b && c";
+    assert render("sort < in | uniq 2>&1 > out")
+        == "sort <in | uniq 2>&1 >out";
+    assert render("make |& less") == "make 2>&1 | less";
+    assert render("echo '$HOME' $HOME >$LOG")
+        == "echo '$HOME' $HOME >$LOG";
+    assert render("cat <<EOF | wc\nhello\nEOF") == "cat <<EOF | wc\nhello\nEOF";
+
+    fn no_files(c: command) -> command { {files: [] with c} }
+    for line in ["  hi there", "! a | b && c", "(a > x; b < y) && c",
+                 "(a; b) | (c; d)", "a && (b || c)", "a && b & a",
+                 "a && b || c; d", "a 2>f | b > g", "a | (b && c) | d",
+                 "a | (b | (c; d | e | f))", "a || b &", "a; ! b",
+                 "cat < in.txt 2> err.log | sort > out.txt",
+                 "cat <<EOF | wc\nhello\nEOF", "cmd > a 2>&1 > b",
+                 "cmd >out >&2", "echo $HOME \"$HOME/bin\" > $LOG",
+                 "echo ${X:-'a b'}", "echo oops 1>&2", "exec 3>&2 4>&3",
+                 "exec 3>socket 5< input", "ls 2>&- >out",
+                 "make &> build.log", "make >> build.log 2>&1",
+                 "make 2>log 1>&2", "sort < in | uniq 2>&1 > out",
+                 "make |& less", "echo a\necho b\n", "a;",
+                 "x && (a | b >f | c)", "cmd >&3 2>&1", "cmd 2>&4 >out"] {
+        let cl = alt parse(tokenizer::tokenize(line)) {
+          parsed(cl) { cl }
+          _ { fail("Expected a command line."); }
+        };
+        alt parse(tokenizer::tokenize(command_line_to_string(cl))) {
+          parsed(back) {
+            assert map_commands(back, no_files) == map_commands(cl, no_files);
+          }
+          _ { assert false; }
+        }
+    }
+}
+
+#[test]
+fn test_command_line_to_json() {
+    fn json(s: str) -> str {
+        ret alt parse(tokenizer::tokenize(s)) {
+          parsed(cl) { command_line_to_json(cl) }
+          _ { fail("Expected a command line."); }
+        };
+    }
+    assert json("ls > out")
+        == "{\"kind\":\"singleton\",\"args\":[\"ls\"],"
+           + "\"input\":{\"kind\":\"stdin\"},"
+           + "\"output\":{\"kind\":\"outfile\",\"file\":\"out\"},"
+           + "\"error\":{\"kind\":\"stderr\"},\"fds\":[],\"closed\":[]}";
+    assert json("! a | b 3>&1 &")
+        == "{\"kind\":\"background\",\"child\":{\"kind\":\"negate\","
+           + "\"child\":{\"kind\":\"pipeline\",\"children\":["
+           + "{\"kind\":\"singleton\",\"args\":[\"a\"],"
+           + "\"input\":{\"kind\":\"stdin\"},\"output\":{\"kind\":\"stdout\"},"
+           + "\"error\":{\"kind\":\"stderr\"},\"fds\":[],\"closed\":[]},"
+           + "{\"kind\":\"singleton\",\"args\":[\"b\"],"
+           + "\"input\":{\"kind\":\"stdin\"},\"output\":{\"kind\":\"stdout\"},"
+           + "\"error\":{\"kind\":\"stderr\"},"
+           + "\"fds\":[{\"fd\":3,\"target\":{\"kind\":\"fd_dup\",\"fd\":1}}],"
+           + "\"closed\":[]}]}}}";
+    assert json("echo \"a\\\"b\\\\c\" <<< \"x\ny\"")
+        == "{\"kind\":\"singleton\",\"args\":[\"echo\",\"a\\\"b\\\\c\"],"
+           + "\"input\":{\"kind\":\"herestring\",\"word\":\"x\\ny\"},"
+           + "\"output\":{\"kind\":\"stdout\"},"
+           + "\"error\":{\"kind\":\"stderr\"},\"fds\":[],\"closed\":[]}";
+}
+
+#[test]
+fn test_fold_command_line() {
+    let cl = alt parse(tokenizer::tokenize("(cat abc d\"e f\\\"\"g;"
+                                           + " echo 'hello\\') |"
+                                           + " grep -i he >matches &")) {
+      parsed(cl) { cl }
+      _ { fail("Expected a command line."); }
+    };
+    assert fold_command_line(cl, 0u) {|n, _c| n + 1u } == 3u;
+    assert fold_command_line(cl, []) {|names, c|
+        names + [tokenizer::word_text(c.args[0u])]
+    }
+        == ["cat", "echo", "grep"];
+    assert fold_command_line(sequence([]), 7u) {|n, _c| n + 1u } == 7u;
+}
@@ -4,65 +4,703 @@ Module: tokenizer
 Command-line tokenizing
 */
 use std;
+import either::either;
+import either::left;
+import either::right;
 
 export token;
 export token_to_string;
+export detokenize;
+export debug_string;
 export tokenize;
+export tokenize_result;
+export tokenizer_config;
+export default_config;
+export tokenize_with;
+export span;
+export tokenize_spans;
+export tokenizer_state;
+export new_state;
+export feed;
+export finish;
+export strip_comments;
+export is_metacharacter;
+export needs_quoting;
+export quote;
+export is_reserved_word;
+export here_document;
+export word_text;
+export parameter_op;
+export brace_body;
 
+/*
+Type: here_document
+
+The body of a <<DELIM or <<-DELIM redirection.
+
+Fields:
+
+delimiter - The delimiter word with its quotes removed.
+body - The lines between the operator's line and the delimiter line,
+       each ending in a newline.
+quoted - Whether any part of the delimiter was quoted, which means the
+         body is to be used literally rather than expanded.
+strip_tabs - Whether the operator was <<-, so leading tabs were removed
+             from the body's lines and the delimiter line.
+*/
+type here_document = {delimiter: str, body: str, quoted: bool,
+                      strip_tabs: bool};
+
+/*
+Type: span
+
+Where a token came from in the command line
+
+Fields:
+
+start - The offset, in characters, of the token's first character.
+end - The offset just past its last character. Whitespace after the token
+      is not included.
+*/
+type span = {start: uint, end: uint};
+
+/*
+Enum: parameter_op
+
+What a ${name:OPword} expansion does when the variable is unset or
+empty, or another way a ${...} uses the variable. The word is quoted as
+usual but may contain blanks, and is empty for the operators that take
+none.
+
+use_default - Expand to the word instead (:-).
+assign_default - Set the variable to the word, then expand to it (:=).
+error_if_unset - Fail with the word as the message (:?).
+use_alternate - Expand to the word only if the variable is set and not
+                empty, and to nothing otherwise (:+).
+indirect - Expand the variable that the value names (${!name}).
+array_keys - Expand to the indices of an array, ${!name[@]} or, for a
+             '*', ${!name[*]}.
+transform - Expand to the value changed as ${name@X} says, X being one
+            of Q (quoted for reuse as input), E (escapes expanded), P
+            (expanded as a prompt), U or L (upper or lower case) and u
+            or l (first character only).
+upper_first - Expand to the value with its first character in upper
+              case if it matches the word, a glob pattern that matches
+              any character when empty (^).
+upper_all - The same for every matching character (^^).
+lower_first - As upper_first, in lower case (,).
+lower_all - As upper_all, in lower case (,,).
+*/
+enum parameter_op {
+    use_default,
+    assign_default,
+    error_if_unset,
+    use_alternate,
+    indirect,
+    array_keys(char),
+    transform(char),
+    upper_first,
+    upper_all,
+    lower_first,
+    lower_all,
+}
+
+/*
+Enum: brace_body
+
+What is between the braces of a brace expansion.
+
+brace_list - The comma-separated alternatives of {a,b,c}, each as
+             written; an alternative may hold further braces.
+brace_range - The ends of {1..5} or {a..e}.
+
+A brace_expand token holds the part of its word before the first such
+braces, the body and the rest of the word, which may be a brace_expand
+token itself. A { without a top-level comma or a range is literal, as
+is an unmatched [ in a glob.
+*/
+enum brace_body {
+    brace_list([str]),
+    brace_range(str, str),
+}
+
+fn brace_body_to_string(body: brace_body) -> str {
+    ret alt body {
+      brace_list(items) { "{" + str::connect(items, ",") + "}" }
+      brace_range(from, to) { "{" + from + ".." + to + "}" }
+    };
+}
+
+fn parameter_op_to_string(op: parameter_op) -> str {
+    ret alt op {
+      use_default { ":-" }
+      assign_default { ":=" }
+      error_if_unset { ":?" }
+      use_alternate { ":+" }
+      indirect { "!" }
+      array_keys(ch) { "![" + str::from_char(ch) + "]" }
+      transform(ch) { "@" + str::from_char(ch) }
+      upper_first { "^" }
+      upper_all { "^^" }
+      lower_first { "," }
+      lower_all { ",," }
+    };
+}
+
+// The ${...} for name, op and the text of op's word.
+fn parameter_text(n: str, op: parameter_op, w: str) -> str {
+    ret alt op {
+      indirect { "${!" + n + "}" }
+      array_keys(ch) { "${!" + n + "[" + str::from_char(ch) + "]}" }
+      _ { "${" + n + parameter_op_to_string(op) + w + "}" }
+    };
+}
+
+// Tokens compare structurally; ordering follows the order of the variants
+// below, then their payloads, so token lists can be sorted for diagnostics.
+// A redirection target or here-string keeps the word token it was written
+// as, so its quoting survives until it is expanded.
 enum token {
     string(str),
+    variable(str),  // $name or ${name}
+    parameter(str, parameter_op, @token),  // ${name:-word}, ${!name}, ...
+    command_subst(str),  // $(command)
+    arith(str),  // $((expression))
+    tilde(str, str),  // ~user/path, holding user (empty for ~) and /path
+    glob(str),  // *.txt, with quoted *, ?, [, ] and \ escaped by \
+    brace_expand(@token, brace_body, @token),  // a{b,c}d
+    word([token]),  // the pieces of one word, e.g. a$b
     pipe,  // |
-    redirect_output(str),  // > file
-    redirect_error(str),  // 2> file
+    pipe_both,  // |&
+    redirect_output(@token),  // > file
+    redirect_output_append(@token),  // >> file
+    redirect_error(@token),  // 2> file
+    redirect_error_append(@token),  // 2>> file
     redirect_error_to_output,  // 2>&1
-    redirect_input(str),  // < file
+    redirect_both(@token),  // &> file
+    redirect_both_append(@token),  // &>> file
+    redirect_input(@token),  // < file
+    here_doc(here_document),  // <<EOF
+    here_string(@token),  // <<< word
+    redirect_fd(uint, @token),  // 3> file
+    redirect_fd_input(uint, @token),  // 3< file
+    fd_here_doc(uint, here_document),  // 3<<EOF
+    fd_here_string(uint, @token),  // 3<<< word
+    dup_fd(uint, uint),  // 3>&2
+    close_fd(uint),  // 3>&-
     and,  // &&
     or,  // ||
     background,  // &
+    negate,  // !
     sequence,  // ;
     open_subshell,  // (
     close_subshell,  // )
-    continuation,  // \
-    error(str),
+    custom_operator(char),  // one of tokenizer_config's custom_operators
+    continuation(str),  // \, or what else is open, as a prompt hint
+    error(str, uint),  // the message and where the problem was found
+}
+
+/*
+Function: is_metacharacter
+
+Whether a character separates words when unquoted
+
+Parameters:
+
+ch - the character to test
+
+Returns:
+
+true for ASCII whitespace and the operator characters | & ; ( ) < >
+*/
+fn is_metacharacter(ch: char) -> bool {
+    if is_space(ch) {
+        ret true;
+    }
+    ret alt ch {
+      '|' | '&' | ';' | '(' | ')' | '<' | '>' { true }
+      _ { false }
+    };
+}
+
+/*
+Function: needs_quoting
+
+Whether a word must be quoted to be read back as itself
+
+Parameters:
+
+s - the word to test
+
+Returns:
+
+true if s is empty or contains a metacharacter, a quoting character, or
+a character with a special meaning to the shell
+*/
+fn needs_quoting(s: str) -> bool {
+    if str::is_empty(s) {
+        ret true;
+    }
+    let c = str::chars(s);
+    if c[0] == '#' || c[0] == '~' {
+        ret true;
+    }
+    for ch in c {
+        if is_metacharacter(ch) {
+            ret true;
+        }
+        alt ch {
+          '\'' | '"' | '\\' | '$' | '`' | '*' | '?' | '[' | '{' | '}'
+          | '!' {
+            ret true;
+          }
+          _ { }
+        }
+    }
+    ret false;
+}
+
+/*
+Function: quote
+
+Quote a word so that it tokenizes back to itself
+
+Parameters:
+
+s - the word to quote
+
+Returns:
+
+s itself if it needs no quoting, otherwise s in single quotes, or in
+double quotes if it contains a single quote
+*/
+fn quote(s: str) -> str {
+    if !needs_quoting(s) {
+        ret s;
+    }
+    let c = str::chars(s);
+    if !vec::contains(c, '\'') {
+        ret "'" + s + "'";
+    }
+    let r = "\"";
+    for ch in c {
+        alt ch {
+          '"' | '\\' | '$' | '`' { str::push_char(r, '\\'); }
+          _ { }
+        }
+        str::push_char(r, ch);
+    }
+    ret r + "\"";
+}
+
+/*
+Function: is_reserved_word
+
+Whether a word is one of the shell's reserved words
+
+Reserved words are only special unquoted, where a command name could
+appear. There the tokenizer turns ! into a negate token; it still
+produces the others as ordinary strings.
+
+Parameters:
+
+s - the word to test
+
+Returns:
+
+true if s is a POSIX or bash reserved word
+*/
+fn is_reserved_word(s: str) -> bool {
+    ret vec::contains(["if", "then", "else", "elif", "fi", "do", "done",
+                       "case", "esac", "while", "until", "for", "in",
+                       "function", "select", "time", "{", "}", "!", "[[",
+                       "]]"], s);
+}
+
+// Whether the piece after pieces[i] would run on into a variable's name
+// if the variable were written without braces.
+fn runs_on(pieces: [token], i: uint) -> bool {
+    ret i + 1u < vec::len(pieces) && alt pieces[i + 1u] {
+      string(s) { !str::is_empty(s) && is_name_char(str::chars(s)[0]) }
+      _ { false }
+    };
+}
+
+fn word_to_string(pieces: [token]) -> str {
+    let r = "";
+    let i = 0u;
+    while i < vec::len(pieces) {
+        r += alt pieces[i] {
+          variable(n) if runs_on(pieces, i) { "${" + n + "}" }
+          _ { token_to_string(pieces[i]) }
+        };
+        i += 1u;
+    }
+    ret r;
+}
+
+// Pattern characters and escapes are kept, and anything else that the
+// tokenizer would treat specially is escaped.
+fn glob_to_string(p: str) -> str {
+    let r = "";
+    let escaped = false;
+    for ch in str::chars(p) {
+        if escaped {
+            str::push_char(r, '\\');
+            str::push_char(r, ch);
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else {
+            let s = "";
+            str::push_char(s, ch);
+            if !is_glob_char(ch) && ch != '!' && needs_quoting(s) {
+                str::push_char(r, '\\');
+            }
+            str::push_char(r, ch);
+        }
+    }
+    ret r;
+}
+
+// An empty preamble or postscript is left out rather than written ''.
+fn brace_part_to_string(t: token) -> str {
+    ret if t == string("") { "" } else { token_to_string(t) };
+}
+
+// Any quoting in the delimiter marks the body as literal, so an unquoted
+// one is written as it was: its text is already what an unquoted word
+// would give.
+fn here_doc_to_string(h: here_document) -> str {
+    let op = if h.strip_tabs { "<<-" } else { "<<" };
+    ret if !h.quoted {
+        op + h.delimiter
+    } else if needs_quoting(h.delimiter) {
+        op + quote(h.delimiter)
+    } else {
+        op + "'" + h.delimiter + "'"
+    };
 }
 
 fn token_to_string(t: token) -> str {
     ret alt t {
-      string(s) { s }
+      string(s) { quote(s) }
+      variable(n) { "$" + n }
+      parameter(n, op, w) { parameter_text(n, op, token_to_string(*w)) }
+      command_subst(s) { "$(" + s + ")" }
+      arith(s) { "$((" + s + "))" }
+      tilde(user, path) {
+        "~" + user + if str::is_empty(path) {
+            ""
+        } else {
+            let rest = str::slice(path, 1u, str::len(path));
+            "/" + if str::is_empty(rest) { "" } else { quote(rest) }
+        }
+      }
+      glob(p) { glob_to_string(p) }
+      brace_expand(pre, body, post) {
+        brace_part_to_string(*pre) + brace_body_to_string(body)
+            + brace_part_to_string(*post)
+      }
+      word(pieces) { word_to_string(pieces) }
       pipe { "|" }
-      redirect_output(s) { ">" + s }
-      redirect_error(s) { "2>" + s }
+      pipe_both { "|&" }
+      redirect_output(w) { ">" + token_to_string(*w) }
+      redirect_output_append(w) { ">>" + token_to_string(*w) }
+      redirect_error(w) { "2>" + token_to_string(*w) }
+      redirect_error_append(w) { "2>>" + token_to_string(*w) }
       redirect_error_to_output { "2>&1" }
-      redirect_input(s) { "<" + s }
+      redirect_both(w) { "&>" + token_to_string(*w) }
+      redirect_both_append(w) { "&>>" + token_to_string(*w) }
+      redirect_input(w) { "<" + token_to_string(*w) }
+      here_doc(h) { here_doc_to_string(h) }
+      here_string(w) { "<<<" + token_to_string(*w) }
+      redirect_fd(fd, w) { uint::to_str(fd, 10u) + ">" + token_to_string(*w) }
+      redirect_fd_input(fd, w) {
+        uint::to_str(fd, 10u) + "<" + token_to_string(*w)
+      }
+      fd_here_doc(fd, h) { uint::to_str(fd, 10u) + here_doc_to_string(h) }
+      fd_here_string(fd, w) {
+        uint::to_str(fd, 10u) + "<<<" + token_to_string(*w)
+      }
+      dup_fd(fd, target) {
+        uint::to_str(fd, 10u) + ">&" + uint::to_str(target, 10u)
+      }
+      close_fd(fd) { uint::to_str(fd, 10u) + ">&-" }
       and { "&&" }
       or { "||" }
       background { "&" }
+      negate { "!" }
       sequence { ";" }
       open_subshell { "(" }
       close_subshell { ")" }
-      continuation { "\\" }
-      error(s) { "Error: '" + s + "'" }
+      custom_operator(ch) { str::from_char(ch) }
+      continuation(_) { "\\" }
+      error(s, _) { "Error: '" + s + "'" }
+    };
+}
+
+/*
+Function: detokenize
+
+Render tokens back into a command line
+
+Parameters:
+
+tokens - the tokens to render, as returned by <tokenize>
+
+Returns:
+
+A command line that <tokenize> turns back into the same tokens, provided
+there are no error or continuation tokens among them. The tokens are
+separated by single spaces, and any here-doc bodies follow the line, in
+order.
+*/
+fn detokenize(tokens: [token]) -> str {
+    let words: [str] = [];
+    let bodies = "";
+    for t in tokens {
+        alt t {
+          here_doc(h) | fd_here_doc(_, h) {
+            bodies += "\n" + h.body + h.delimiter;
+          }
+          _ { }
+        }
+        words += [token_to_string(t)];
+    }
+    ret str::connect(words, " ") + bodies;
+}
+
+fn debug_quote(s: str) -> str {
+    let r = "\"";
+    for ch in str::chars(s) {
+        alt ch {
+          '"' | '\\' {
+            str::push_char(r, '\\');
+            str::push_char(r, ch);
+          }
+          '\n' { r += "\\n"; }
+          '\t' { r += "\\t"; }
+          _ { str::push_char(r, ch); }
+        }
+    }
+    ret r + "\"";
+}
+
+fn bool_to_string(b: bool) -> str {
+    ret if b { "true" } else { "false" };
+}
+
+fn here_doc_to_debug_string(h: here_document) -> str {
+    ret debug_quote(h.delimiter) + ", " + debug_quote(h.body) + ", "
+        + bool_to_string(h.quoted) + ", " + bool_to_string(h.strip_tabs);
+}
+
+fn token_to_debug_string(t: token) -> str {
+    ret alt t {
+      string(s) { "string(" + debug_quote(s) + ")" }
+      variable(n) { "variable(" + debug_quote(n) + ")" }
+      parameter(n, op, w) {
+        "parameter(" + debug_quote(n) + ", "
+            + debug_quote(parameter_op_to_string(op)) + ", "
+            + token_to_debug_string(*w) + ")"
+      }
+      command_subst(s) { "command_subst(" + debug_quote(s) + ")" }
+      arith(s) { "arith(" + debug_quote(s) + ")" }
+      tilde(user, path) {
+        "tilde(" + debug_quote(user) + ", " + debug_quote(path) + ")"
+      }
+      glob(p) { "glob(" + debug_quote(p) + ")" }
+      brace_expand(pre, body, post) {
+        "brace_expand(" + token_to_debug_string(*pre) + ", "
+            + debug_quote(brace_body_to_string(body)) + ", "
+            + token_to_debug_string(*post) + ")"
+      }
+      word(pieces) {
+        let ps: [str] = [];
+        for p in pieces {
+            ps += [token_to_debug_string(p)];
+        }
+        "word(" + str::connect(ps, ", ") + ")"
+      }
+      pipe { "pipe" }
+      pipe_both { "pipe_both" }
+      redirect_output(w) {
+        "redirect_output(" + token_to_debug_string(*w) + ")"
+      }
+      redirect_output_append(w) {
+        "redirect_output_append(" + token_to_debug_string(*w) + ")"
+      }
+      redirect_error(w) { "redirect_error(" + token_to_debug_string(*w) + ")" }
+      redirect_error_append(w) {
+        "redirect_error_append(" + token_to_debug_string(*w) + ")"
+      }
+      redirect_error_to_output { "redirect_error_to_output" }
+      redirect_both(w) { "redirect_both(" + token_to_debug_string(*w) + ")" }
+      redirect_both_append(w) {
+        "redirect_both_append(" + token_to_debug_string(*w) + ")"
+      }
+      redirect_input(w) { "redirect_input(" + token_to_debug_string(*w) + ")" }
+      here_doc(h) { "here_doc(" + here_doc_to_debug_string(h) + ")" }
+      here_string(w) { "here_string(" + token_to_debug_string(*w) + ")" }
+      redirect_fd(fd, w) {
+        "redirect_fd(" + uint::to_str(fd, 10u) + ", "
+            + token_to_debug_string(*w) + ")"
+      }
+      redirect_fd_input(fd, w) {
+        "redirect_fd_input(" + uint::to_str(fd, 10u) + ", "
+            + token_to_debug_string(*w) + ")"
+      }
+      fd_here_doc(fd, h) {
+        "fd_here_doc(" + uint::to_str(fd, 10u) + ", "
+            + here_doc_to_debug_string(h) + ")"
+      }
+      fd_here_string(fd, w) {
+        "fd_here_string(" + uint::to_str(fd, 10u) + ", "
+            + token_to_debug_string(*w) + ")"
+      }
+      dup_fd(fd, target) {
+        "dup_fd(" + uint::to_str(fd, 10u) + ", " + uint::to_str(target, 10u)
+            + ")"
+      }
+      close_fd(fd) { "close_fd(" + uint::to_str(fd, 10u) + ")" }
+      and { "and" }
+      or { "or" }
+      background { "background" }
+      negate { "negate" }
+      sequence { "sequence" }
+      open_subshell { "open_subshell" }
+      close_subshell { "close_subshell" }
+      custom_operator(ch) {
+        "custom_operator(" + debug_quote(str::from_char(ch)) + ")"
+      }
+      continuation(r) { "continuation(" + debug_quote(r) + ")" }
+      error(s, at) {
+        "error(" + debug_quote(s) + ", " + uint::to_str(at, 10u) + ")"
+      }
     };
 }
 
+/*
+Function: debug_string
+
+Render tokens for debugging output
+
+Parameters:
+
+tokens - the tokens to render
+
+Returns:
+
+A string with one line per token, giving the variant name and its payload
+*/
+fn debug_string(tokens: [token]) -> str {
+    let s = "";
+    for t in tokens {
+        s += token_to_debug_string(t) + "\n";
+    }
+    ret s;
+}
+
 type consumption = {
     t: token,
     offset: uint,
 };
 
-fn make_string_consumption(c: [char], offset: uint, end: uint) -> consumption {
-    ret {t: string(str::from_chars(vec::slice(c, offset, end))),
-         offset: end};
+fn make_string_consumption(c: str, offset: uint, end: uint) -> consumption {
+    ret {t: string(str::slice(c, offset, end)), offset: end};
+}
+
+/*
+Type: tokenizer_config
+
+The characters that split a command line into tokens
+
+Fields:
+
+operators - the operator characters to recognize; only the shell's own
+            operator characters (< > ; & | ( )) have any effect, and one
+            left out of the list is treated as an ordinary word character
+separators - characters that separate words in addition to whitespace
+custom_operators - further characters that end a word and stand alone as
+                   a custom_operator token, unless quoted or escaped; one
+                   of the shell's operator characters listed here loses
+                   its shell meaning
+*/
+type tokenizer_config = {
+    operators: [char],
+    separators: [char],
+    custom_operators: [char],
+};
+
+/*
+Function: default_config
+
+The tokenizer configuration used by <tokenize>
+
+Returns:
+
+A tokenizer_config recognizing every shell operator, and no extra
+separators or custom operators
+*/
+fn default_config() -> tokenizer_config {
+    ret {operators: ['<', '>', ';', '&', '|', '(', ')'], separators: [],
+         custom_operators: []};
+}
+
+fn is_operator(cfg: tokenizer_config, ch: char) -> bool {
+    ret vec::contains(cfg.custom_operators, ch) || alt ch {
+      '<' | '>' | ';' | '&' | '|' | '(' | ')' {
+        vec::contains(cfg.operators, ch)
+      }
+      _ { false }
+    };
+}
+
+// Only ASCII whitespace separates words, as in POSIX sh. Other Unicode
+// spaces, such as U+00A0 NO-BREAK SPACE, are ordinary word characters, so
+// text pasted with them keeps its words together rather than splitting
+// differently depending on the locale.
+fn is_space(ch: char) -> bool {
+    ret alt ch {
+      ' ' | '\t' | '\n' | '\r' | '\x0b' | '\x0c' { true }
+      _ { false }
+    };
+}
+
+// Newlines are not blank: they separate commands, like ';'.
+fn is_blank(cfg: tokenizer_config, ch: char) -> bool {
+    ret (is_space(ch) && ch != '\n') || vec::contains(cfg.separators, ch);
+}
+
+// The command line is walked by byte offset, which saves building a
+// vector of its characters. Every character given a meaning of its own
+// is ASCII, so it is a single byte, but an offset must still be kept at
+// the start of a character: a loop that steps over arbitrary characters
+// uses next_char rather than adding one.
+fn at(c: str, i: uint) -> char {
+    ret if c[i] < 128u8 { c[i] as char } else { str::char_at(c, i) };
+}
+
+// The offset of the character after the one at c[i]. Like adding one,
+// it goes on past the end of c.
+fn next_char(c: str, i: uint) -> uint {
+    ret if i >= str::len(c) || c[i] < 128u8 {
+        i + 1u
+    } else {
+        str::char_range_at(c, i).next
+    };
 }
 
-fn is_token_separator(c: [char], offset: uint) -> bool {
-    if str::is_whitespace(str::from_char(c[offset])) {
+fn is_token_separator(cfg: tokenizer_config, c: str, offset: uint)
+    -> bool {
+    let ch = at(c, offset);
+    if is_blank(cfg, ch) || is_operator(cfg, ch) || ch == '\n' {
         true
     } else {
-        alt c[offset] {
-          '<' | '>' | ';' | '&' | '|' | '(' | ')' { true }
+        alt ch {
           '\\' {
-            if offset + 1u == vec::len(c) {
+            if offset + 1u == str::len(c) {
                 true
             } else {
                 false
@@ -73,166 +711,683 @@ fn is_token_separator(c: [char], offset: uint) -> bool {
     }
 }
 
-fn consume_whitespace(c: [char], offset: uint) -> consumption {
+// A backslash immediately followed by a newline joins two physical lines
+// into one; both characters vanish.
+fn is_line_join(c: str, offset: uint) -> bool {
+    ret at(c, offset) == '\\' && offset + 1u < str::len(c)
+        && at(c, offset + 1u) == '\n';
+}
+
+fn consume_whitespace(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
     let end = offset;
-    while (end < vec::len(c) 
-           && str::is_whitespace(str::from_char(c[end]))) {
-        end += 1u;
+    while end < str::len(c) {
+        if is_blank(cfg, at(c, end)) {
+            end = next_char(c, end);
+        } else if is_line_join(c, end) {
+            end += 2u;
+        } else {
+            break;
+        }
     }
     ret make_string_consumption(c, offset, end);
 }
 
-fn consume_or(c: [char], offset: uint) -> consumption {
-    if vec::len(c) < offset + 2u || c[offset] != '|' || c[offset + 1u] != '|' {
-        fail(#fmt("Tried to consume || at %u of '%s'.",
-                  offset, str::from_chars(c)));
+fn consume_or(c: str, offset: uint) -> consumption {
+    if str::len(c) < offset + 2u || at(c, offset) != '|'
+        || at(c, offset + 1u) != '|' {
+        fail(#fmt("Tried to consume || at %u of '%s'.", offset, c));
     }
     ret {t: or, offset: offset + 2u};
 }
 
-fn consume_pipe(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '|';
+fn consume_pipe(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '|';
     ret {t: pipe, offset: offset + 1u};
 }
 
-fn consume_pipechar(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '|';
-    if offset + 1u < vec::len(c) && c[offset + 1u] == '|' {
+// "|&" is one operator, so "a |& b" never reads as "a |" then "& b".
+fn consume_pipechar(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '|';
+    if offset + 1u < str::len(c) && at(c, offset + 1u) == '|' {
         consume_or(c, offset)
+    } else if offset + 1u < str::len(c) && at(c, offset + 1u) == '&' {
+        {t: pipe_both, offset: offset + 2u}
     } else {
         consume_pipe(c, offset)
     }
 }
 
-fn consume_and(c: [char], offset: uint) -> consumption {
-    if vec::len(c) < offset + 2u || c[offset] != '&' || c[offset + 1u] != '&' {
-        fail(#fmt("Tried to consume && at %u of '%s'.",
-                  offset, str::from_chars(c)));
+fn consume_and(c: str, offset: uint) -> consumption {
+    if str::len(c) < offset + 2u || at(c, offset) != '&'
+        || at(c, offset + 1u) != '&' {
+        fail(#fmt("Tried to consume && at %u of '%s'.", offset, c));
     }
     ret {t: and, offset: offset + 2u};
 }
 
-fn consume_background(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '&';
+fn consume_background(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '&';
     ret {t: background, offset: offset + 1u};
 }
 
-fn consume_ampersand(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '&';
-    if offset + 1u < vec::len(c) && c[offset + 1u] == '&' {
+fn consume_redirect_both(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '&';
+    assert at(c, offset + 1u) == '>';
+    // Only a contiguous "&>>" appends; "&> >" is "&>" missing its file.
+    let append = offset + 2u < str::len(c) && at(c, offset + 2u) == '>';
+    let start = if append { offset + 3u } else { offset + 2u };
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, start);
+    let r = consume_string(cfg, c, ws_offset);
+    ret alt r.t {
+      // The word may end in an unfinished $((, which asks for more.
+      continuation(_) { r }
+      error(_, _) {
+        {t: error("Could not parse file name for output redirection.",
+                  ws_offset),
+         offset: str::len(c) }
+      }
+      _ if str::is_empty(word_text(r.t)) {
+        {t: error("No output file specified.", ws_offset),
+         offset: str::len(c)}
+      }
+      _ if append { {t: redirect_both_append(@r.t), offset: r.offset} }
+      _ { {t: redirect_both(@r.t), offset: r.offset} }
+    };
+}
+
+// "&&" always wins over "&", so "a &&>b" is "a &&" followed by ">b": the
+// redirect starts the next command rather than forming part of "&>". Only
+// a contiguous "&>" redirects both streams; "a & >b" backgrounds "a".
+fn consume_ampersand(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '&';
+    if offset + 1u < str::len(c) && at(c, offset + 1u) == '&' {
         consume_and(c, offset)
+    } else if offset + 1u < str::len(c) && at(c, offset + 1u) == '>'
+        && is_operator(cfg, '>') {
+        consume_redirect_both(cfg, c, offset)
     } else {
         consume_background(c, offset)
     }
 }
 
-fn consume_redirect_error(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '2';
-    assert c[offset + 1u] == '>';
-    let {t:_, offset: ws_offset} = consume_whitespace(c, offset + 2u);
-    ret alt consume_string(c, ws_offset) {
-      {t: string(file_name), offset: end} {
-        if str::len(file_name) > 0u {
-            {t: redirect_error(file_name), offset: end}
-        } else {
-            {t: error("No error file specified."), offset: vec::len(c) }
-        }
+fn consume_redirect_error(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '2';
+    assert at(c, offset + 1u) == '>';
+    let append = offset + 2u < str::len(c) && at(c, offset + 2u) == '>';
+    let start = if append { offset + 3u } else { offset + 2u };
+    if append && start < str::len(c) && at(c, start) == '&' {
+        // "2>>&1" would append to a descriptor, which means nothing.
+        ret {t: error("Cannot append to a file descriptor.", start),
+             offset: str::len(c)};
+    }
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, start);
+    let r = consume_string(cfg, c, ws_offset);
+    ret alt r.t {
+      continuation(_) { r }
+      error(_, _) {
+        {t: error("Could not parse file name for error redirection.",
+                  ws_offset),
+         offset: str::len(c) }
       }
-      _ {
-        {t: error("Could not parse file name for error redirection."),
-         offset: vec::len(c) }
+      _ if str::is_empty(word_text(r.t)) {
+        {t: error("No error file specified.", ws_offset),
+         offset: str::len(c)}
       }
+      _ if append { {t: redirect_error_append(@r.t), offset: r.offset} }
+      _ { {t: redirect_error(@r.t), offset: r.offset} }
     }
 }
 
-fn consume_two(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '2';
-    if offset + 3u < vec::len(c)
-        && c[offset + 1u] == '>'
-        && c[offset + 2u] == '&'
-        && c[offset + 3u] == '1'
-        && (offset + 4u == vec::len(c) || is_token_separator(c, offset + 4u)) {
+fn consume_two(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '2';
+    if offset + 3u < str::len(c)
+        && at(c, offset + 1u) == '>'
+        && at(c, offset + 2u) == '&'
+        && at(c, offset + 3u) == '1'
+        && (offset + 4u == str::len(c)
+            || is_token_separator(cfg, c, offset + 4u)) {
         {t: redirect_error_to_output, offset: offset + 4u}
-    } else if offset + 1u < vec::len(c) && c[offset + 1u] == '>' {
-        consume_redirect_error(c, offset)
+    } else if offset + 3u < str::len(c)
+        && at(c, offset + 1u) == '>'
+        && at(c, offset + 2u) == '&'
+        && (char::is_digit(at(c, offset + 3u))
+            || at(c, offset + 3u) == '-') {
+        consume_dup(cfg, c, 2u, offset + 3u)
+    } else if offset + 1u < str::len(c) && at(c, offset + 1u) == '>' {
+        consume_redirect_error(cfg, c, offset)
     } else {
-        consume_string(c, offset)
+        consume_string(cfg, c, offset)
+    }
+}
+
+fn consume_fd_file(cfg: tokenizer_config, c: str, fd: uint, input: bool,
+                   offset: uint) -> consumption {
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, offset);
+    let what = if input { "input" } else { "output" };
+    let r = consume_string(cfg, c, ws_offset);
+    ret alt r.t {
+      continuation(_) { r }
+      error(_, _) {
+        {t: error("Could not parse file name for " + what
+                  + " redirection.", ws_offset),
+         offset: str::len(c) }
+      }
+      _ if str::is_empty(word_text(r.t)) {
+        {t: error("No " + what + " file specified.", ws_offset),
+         offset: str::len(c)}
+      }
+      _ if input { {t: redirect_fd_input(fd, @r.t), offset: r.offset} }
+      _ { {t: redirect_fd(fd, @r.t), offset: r.offset} }
+    };
+}
+
+// Consume the descriptor after ">&", or the '-' that closes fd instead;
+// offset is just past the '&'.
+fn consume_dup(cfg: tokenizer_config, c: str, fd: uint, offset: uint)
+    -> consumption {
+    if offset < str::len(c) && at(c, offset) == '-' {
+        if offset + 1u < str::len(c)
+            && !is_token_separator(cfg, c, offset + 1u) {
+            ret {t: error("Expected a separator after '>&-'.", offset + 1u),
+                 offset: str::len(c)};
+        }
+        ret {t: close_fd(fd), offset: offset + 1u};
+    }
+    let target = 0u;
+    let end = offset;
+    while end < str::len(c) && char::is_digit(at(c, end)) {
+        target = target * 10u + (at(c, end) as uint - '0' as uint);
+        end = next_char(c, end);
+    }
+    if end == offset
+        || (end < str::len(c) && !is_token_separator(cfg, c, end)) {
+        ret {t: error("Expected a file descriptor after '>&'.", offset),
+             offset: str::len(c)};
     }
+    ret {t: dup_fd(fd, target), offset: end};
 }
 
-fn consume_redirect_output(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '>';
-    let {t:_, offset: ws_offset} = consume_whitespace(c, offset + 1u);
-    ret alt consume_string(c, ws_offset) {
-      {t: string(file_name), offset: end} {
-        if str::len(file_name) > 0u {
-            {t: redirect_output(file_name), offset: end}
+// A run of digits at the start of a token names a descriptor when it is
+// immediately followed by '>' or '<'. Descriptors 0, 1 and 2 keep their
+// dedicated tokens, so "1>f" is just ">f" and "2>&1" is unchanged. Digits
+// glued to a word ("cmd3>x") never get here: the word is consumed first.
+fn consume_fd(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    let fd = 0u;
+    let end = offset;
+    while end < str::len(c) && char::is_digit(at(c, end)) {
+        fd = fd * 10u + (at(c, end) as uint - '0' as uint);
+        end = next_char(c, end);
+    }
+    if end == str::len(c) || !is_operator(cfg, at(c, end)) {
+        ret consume_string(cfg, c, offset);
+    }
+    ret alt at(c, end) {
+      '>' {
+        if fd != 2u && end + 1u < str::len(c) && at(c, end + 1u) == '&' {
+            consume_dup(cfg, c, fd, end + 2u)
+        } else if fd == 1u {
+            consume_redirect_output(cfg, c, end)
+        } else if fd == 2u {
+            // Leading zeros are allowed, so the last digit is the '2'.
+            consume_two(cfg, c, end - 1u)
+        } else if end + 1u < str::len(c) && at(c, end + 1u) == '>' {
+            {t: error("Appending to descriptor " + uint::to_str(fd, 10u)
+                      + " is not supported.", offset),
+             offset: str::len(c)}
         } else {
-            {t: error("No output file specified."), offset: vec::len(c) }
+            consume_fd_file(cfg, c, fd, false, end + 1u)
         }
       }
-      _ {
-        {t: error("Could not parse file name for output redirection."),
-         offset: vec::len(c) }
+      '<' {
+        if fd == 0u {
+            consume_redirect_input(cfg, c, end)
+        } else if end + 1u < str::len(c) && at(c, end + 1u) == '<' {
+            with_fd(fd, consume_redirect_input(cfg, c, end))
+        } else {
+            consume_fd_file(cfg, c, fd, true, end + 1u)
+        }
+      }
+      _ { consume_string(cfg, c, offset) }
+    };
+}
+
+// A here-doc or here-string, moved onto descriptor fd.
+fn with_fd(fd: uint, r: consumption) -> consumption {
+    ret alt r.t {
+      here_doc(h) { {t: fd_here_doc(fd, h), offset: r.offset} }
+      here_string(w) { {t: fd_here_string(fd, w), offset: r.offset} }
+      _ { r }
+    };
+}
+
+fn consume_redirect_output(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '>';
+    if offset + 1u < str::len(c) && at(c, offset + 1u) == '&' {
+        // ">&2" is shorthand for "1>&2".
+        ret consume_dup(cfg, c, 1u, offset + 2u);
+    }
+    let append = offset + 1u < str::len(c) && at(c, offset + 1u) == '>';
+    let start = if append { offset + 2u } else { offset + 1u };
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, start);
+    let r = consume_string(cfg, c, ws_offset);
+    ret alt r.t {
+      continuation(_) { r }
+      error(_, _) {
+        {t: error("Could not parse file name for output redirection.",
+                  ws_offset),
+         offset: str::len(c) }
       }
+      _ if str::is_empty(word_text(r.t)) {
+        {t: error("No output file specified.", ws_offset),
+         offset: str::len(c)}
+      }
+      _ if append { {t: redirect_output_append(@r.t), offset: r.offset} }
+      _ { {t: redirect_output(@r.t), offset: r.offset} }
     };
 }
         
-fn consume_redirect_input(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '<';
-    let {t:_, offset: ws_offset} = consume_whitespace(c, offset + 1u);
-    ret alt consume_string(c, ws_offset) {
-      {t: string(file_name), offset: end} {
-        if str::len(file_name) > 0u {
-            {t: redirect_input(file_name), offset: end}
+// Only the delimiter is consumed here; tokenize_with collects the body.
+fn consume_here_doc(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '<';
+    assert at(c, offset + 1u) == '<';
+    let strip_tabs = offset + 2u < str::len(c) && at(c, offset + 2u) == '-';
+    let start = if strip_tabs { offset + 3u } else { offset + 2u };
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, start);
+    ret alt consume_literal(cfg, c, ws_offset) {
+      {t: string(delimiter), offset: end} {
+        if str::len(delimiter) > 0u {
+            let quoted = false;
+            for ch in str::chars(str::slice(c, ws_offset, end)) {
+                if ch == '\'' || ch == '"' || ch == '\\' { quoted = true; }
+            }
+            {t: here_doc({delimiter: delimiter, body: "", quoted: quoted,
+                          strip_tabs: strip_tabs}),
+             offset: end}
         } else {
-            {t: error("No input file specified."), offset: vec::len(c) }
+            {t: error("No here-doc delimiter specified.", ws_offset),
+             offset: str::len(c)}
         }
       }
       _ {
-        {t: error("Could not parse file name for input redirection."),
-         offset: vec::len(c)}
+        {t: error("Could not parse here-doc delimiter.", ws_offset),
+         offset: str::len(c)}
       }
     };
 }
 
-fn consume_sequence(c: [char], offset: uint) -> consumption {
-    assert c[offset] == ';';
-    ret {t: sequence, offset: offset + 1u};
+fn consume_here_string(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '<';
+    assert at(c, offset + 1u) == '<';
+    assert at(c, offset + 2u) == '<';
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, offset + 3u);
+    let r = consume_string(cfg, c, ws_offset);
+    ret alt r.t {
+      continuation(_) { r }
+      error(_, _) {
+        {t: error("Could not parse here-string.", ws_offset),
+         offset: str::len(c)}
+      }
+      // An empty word is fine as long as it was written, e.g. <<< "".
+      _ if r.offset == ws_offset {
+        {t: error("No here-string specified.", ws_offset),
+         offset: str::len(c)}
+      }
+      _ { {t: here_string(@r.t), offset: r.offset} }
+    };
 }
 
-fn consume_open_subshell(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '(';
-    ret {t: open_subshell, offset: offset + 1u};
+fn consume_redirect_input(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    assert at(c, offset) == '<';
+    if offset + 2u < str::len(c) && at(c, offset + 1u) == '<'
+        && at(c, offset + 2u) == '<' {
+        ret consume_here_string(cfg, c, offset);
+    }
+    if offset + 1u < str::len(c) && at(c, offset + 1u) == '<' {
+        ret consume_here_doc(cfg, c, offset);
+    }
+    let {t:_, offset: ws_offset} = consume_whitespace(cfg, c, offset + 1u);
+    let r = consume_string(cfg, c, ws_offset);
+    ret alt r.t {
+      continuation(_) { r }
+      error(_, _) {
+        {t: error("Could not parse file name for input redirection.",
+                  ws_offset),
+         offset: str::len(c)}
+      }
+      _ if str::is_empty(word_text(r.t)) {
+        {t: error("No input file specified.", ws_offset),
+         offset: str::len(c)}
+      }
+      _ { {t: redirect_input(@r.t), offset: r.offset} }
+    };
 }
 
-fn consume_close_subshell(c: [char], offset: uint) -> consumption {
-    assert c[offset] == ')';
+fn consume_sequence(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == ';';
+    ret {t: sequence, offset: offset + 1u};
+}
+
+fn consume_open_subshell(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '(';
+    ret {t: open_subshell, offset: offset + 1u};
+}
+
+fn consume_close_subshell(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == ')';
     ret {t: close_subshell, offset: offset + 1u};
 }
 
-fn consume_singleq(c: [char], offset: uint) -> consumption {
-    assert c[offset] == '\'';
+fn consume_singleq(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '\'';
     let end = offset + 1u;
-    while end < vec::len(c) && c[end] != '\'' {
-        end += 1u;
+    while end < str::len(c) && at(c, end) != '\'' {
+        end = next_char(c, end);
     }
-    ret if end == vec::len(c) {
-        {t: error("Missing '."), offset: end}
+    ret if end == str::len(c) {
+        {t: error("Missing '.", offset), offset: end}
     } else {
         let t = make_string_consumption(c, offset + 1u, end);
         {t: t.t, offset: t.offset + 1u}
     };
 }
 
-fn consume_doubleq(c: [char], offset: uint) -> consumption {
-    #debug("consume_doubleq called: '%s', %u", str::from_chars(c), offset);
-    assert c[offset] == '"';
+fn is_name_start(ch: char) -> bool {
+    ret (ch >= 'a' && ch <= 'z') || (ch >= 'A' && ch <= 'Z') || ch == '_';
+}
+
+fn is_name_char(ch: char) -> bool {
+    ret is_name_start(ch) || (ch >= '0' && ch <= '9');
+}
+
+// The word after the operator in ${name:-word} and the like, up to the
+// closing brace. It is quoted as usual, but blanks and operator
+// characters are part of it. brace is where the ${ began.
+fn consume_parameter_word(c: str, brace: uint, offset: uint)
+    -> consumption {
+    let pieces: [token] = [];
+    let s: str = "";
+    let end = offset;
+    while end < str::len(c) && at(c, end) != '}' {
+        alt at(c, end) {
+          '"' | '$' {
+            let r = if at(c, end) == '"' {
+                consume_doubleq(c, end)
+            } else {
+                consume_dollar(c, end)
+            };
+            alt r.t {
+              error(_, _) | continuation(_) { ret r; }
+              _ { }
+            }
+            add_piece(pieces, s, r.t);
+            end = r.offset;
+          }
+          '\'' {
+            let r = consume_singleq(c, end);
+            alt r.t {
+              string(qs) { s += qs; }
+              _ { ret r; }
+            }
+            end = r.offset;
+          }
+          '\\' if end + 1u < str::len(c) {
+            str::push_char(s, at(c, end + 1u));
+            end = next_char(c, end + 1u);
+          }
+          _ {
+            str::push_char(s, at(c, end));
+            end = next_char(c, end);
+          }
+        }
+    }
+    ret if end == str::len(c) {
+        {t: error("Missing }.", brace), offset: end}
+    } else {
+        {t: finish_word(pieces, s), offset: end + 1u}
+    };
+}
+
+fn consume_braced_variable(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '$';
+    assert at(c, offset + 1u) == '{';
+    let bang = offset + 2u < str::len(c) && at(c, offset + 2u) == '!';
+    let start = if bang { offset + 3u } else { offset + 2u };
+    let end = start;
+    while end < str::len(c) && is_name_char(at(c, end)) {
+        end += 1u;
+    }
+    if !str::contains(str::slice(c, end, str::len(c)), "}") {
+        ret {t: error("Missing }.", offset), offset: str::len(c)};
+    }
+    let bad = {t: error("Bad substitution.", offset), offset: str::len(c)};
+    if end == start || !is_name_start(at(c, start)) {
+        ret bad;
+    }
+    let name = str::slice(c, start, end);
+    if bang {
+        if at(c, end) == '}' {
+            ret {t: parameter(name, indirect, @string("")),
+                 offset: end + 1u};
+        }
+        if end + 3u < str::len(c) && at(c, end) == '['
+            && (at(c, end + 1u) == '@' || at(c, end + 1u) == '*')
+            && at(c, end + 2u) == ']' && at(c, end + 3u) == '}' {
+            ret {t: parameter(name, array_keys(at(c, end + 1u)),
+                              @string("")),
+                 offset: end + 4u};
+        }
+        ret bad;
+    }
+    if at(c, end) == '@' {
+        let ch = at(c, end + 1u);
+        let close = next_char(c, end + 1u);
+        if ch == '}' || at(c, close) != '}' {
+            ret bad;
+        }
+        if !str::contains("QEPULul", str::from_char(ch)) {
+            ret {t: error(#fmt("Unknown transformation operator '@%c'.",
+                               ch),
+                          offset),
+                 offset: str::len(c)};
+        }
+        ret {t: parameter(name, transform(ch), @string("")),
+             offset: close + 1u};
+    }
+    if at(c, end) == '^' || at(c, end) == ',' {
+        let ch = at(c, end);
+        let all = at(c, end + 1u) == ch;
+        let op = if ch == '^' {
+            if all { upper_all } else { upper_first }
+        } else {
+            if all { lower_all } else { lower_first }
+        };
+        let r = consume_parameter_word(c, offset,
+                                       if all { end + 2u } else { end + 1u });
+        ret alt r.t {
+          error(_, _) | continuation(_) { r }
+          _ { {t: parameter(name, op, @r.t), offset: r.offset} }
+        };
+    }
+    if at(c, end) != '}' && at(c, end) != ':' {
+        ret bad;
+    }
+    if at(c, end) == '}' {
+        ret {t: variable(name), offset: end + 1u};
+    }
+    let op = alt at(c, end + 1u) {
+      '-' { use_default }
+      '=' { assign_default }
+      '?' { error_if_unset }
+      '+' { use_alternate }
+      ch {
+        ret {t: error(#fmt("Unknown expansion operator ':%c'.", ch),
+                      offset),
+             offset: str::len(c)};
+      }
+    };
+    let r = consume_parameter_word(c, offset, end + 2u);
+    ret alt r.t {
+      error(_, _) | continuation(_) { r }
+      _ { {t: parameter(name, op, @r.t), offset: r.offset} }
+    };
+}
+
+// The command is kept as text to be parsed when it is run. Quoted and
+// escaped parentheses do not count towards the nesting.
+fn consume_command_subst(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '$';
+    assert at(c, offset + 1u) == '(';
+    let start = offset + 2u;
+    let end = start;
+    let depth = 1u;
+    while end < str::len(c) {
+        alt at(c, end) {
+          '(' { depth += 1u; }
+          ')' {
+            depth -= 1u;
+            if depth == 0u {
+                break;
+            }
+          }
+          '\\' { end += 1u; }
+          '\'' | '"' {
+            let q = at(c, end);
+            end += 1u;
+            while end < str::len(c) && at(c, end) != q {
+                if q == '"' && at(c, end) == '\\' {
+                    end += 1u;
+                }
+                end = next_char(c, end);
+            }
+          }
+          _ { }
+        }
+        end = next_char(c, end);
+    }
+    ret if end >= str::len(c) {
+        {t: error("Missing ).", offset), offset: str::len(c)}
+    } else {
+        {t: command_subst(str::slice(c, start, end)), offset: end + 1u}
+    };
+}
+
+// As in bash, "$((" that does not end in a matching "))" is a command
+// substitution whose command starts with a subshell, e.g. $((cd x) ).
+// One that the input ends inside asks for another line.
+fn consume_arith(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '$';
+    assert at(c, offset + 1u) == '(';
+    assert at(c, offset + 2u) == '(';
+    let start = offset + 3u;
+    let end = start;
+    let depth = 0u;
+    while end < str::len(c) {
+        alt at(c, end) {
+          '(' { depth += 1u; }
+          ')' {
+            if depth > 0u {
+                depth -= 1u;
+            } else if end + 1u < str::len(c) && at(c, end + 1u) == ')' {
+                ret {t: arith(str::slice(c, start, end)),
+                     offset: end + 2u};
+            } else {
+                ret consume_command_subst(c, offset);
+            }
+          }
+          _ { }
+        }
+        end = next_char(c, end);
+    }
+    ret {t: continuation("arith>"), offset: str::len(c)};
+}
+
+// A $ not followed by a name, a { or a ( is an ordinary character.
+fn consume_dollar(c: str, offset: uint) -> consumption {
+    assert at(c, offset) == '$';
+    let end = offset + 1u;
+    if end < str::len(c) && at(c, end) == '{' {
+        ret consume_braced_variable(c, offset);
+    }
+    if end + 1u < str::len(c) && at(c, end) == '(' && at(c, end + 1u) == '(' {
+        ret consume_arith(c, offset);
+    }
+    if end < str::len(c) && at(c, end) == '(' {
+        ret consume_command_subst(c, offset);
+    }
+    if end == str::len(c) || !is_name_start(at(c, end)) {
+        ret {t: string("$"), offset: end};
+    }
+    while end < str::len(c) && is_name_char(at(c, end)) {
+        end += 1u;
+    }
+    ret {t: variable(str::slice(c, offset + 1u, end)), offset: end};
+}
+
+// Adds a string, variable or word to the word being built. Text is
+// collected in s until a variable needs to follow it in pieces.
+fn add_piece(&pieces: [token], &s: str, t: token) {
+    alt t {
+      string(text) { s += text; }
+      word(ps) {
+        for p in ps {
+            add_piece(pieces, s, p);
+        }
+      }
+      _ {
+        if !str::is_empty(s) {
+            pieces += [string(s)];
+            s = "";
+        }
+        pieces += [t];
+      }
+    }
+}
+
+// A word with no variables is a plain string, and one that is nothing but
+// a variable is that variable.
+fn finish_word(pieces: [token], s: str) -> token {
+    if vec::is_empty(pieces) {
+        ret string(s);
+    }
+    let all = if str::is_empty(s) { pieces } else { pieces + [string(s)] };
+    ret if vec::len(all) == 1u { all[0u] } else { word(all) };
+}
+
+// The offset of the " closing the double-quoted string at c[offset], or
+// the end of c if there is none. A $( inside may hold a quote of its own,
+// so this can be short of the real close.
+fn closing_quote(c: str, offset: uint) -> uint {
+    let end = offset + 1u;
+    while end < str::len(c) && at(c, end) != '"' {
+        end = if at(c, end) == '\\' { next_char(c, end + 1u) }
+              else { next_char(c, end) };
+    }
+    ret if end > str::len(c) { str::len(c) } else { end };
+}
+
+// As in POSIX sh, a backslash only escapes \, ", $, ` and newline
+// inside double quotes, and is kept before anything else.
+fn consume_doubleq(c: str, offset: uint) -> consumption {
+    #debug("consume_doubleq called: '%s', %u", c, offset);
+    assert at(c, offset) == '"';
+    let pieces: [token] = [];
     let s: str = "";
+    // The text is never longer than the quoted source.
+    str::reserve(s, closing_quote(c, offset) - offset);
     let end = offset + 1u;
-    while end < vec::len(c) && c[end] != '"' {
-        if c[end] == '\\' && end + 1u != vec::len(c) {
-            alt c[end + 1u] {
+    while end < str::len(c) && at(c, end) != '"' {
+        if at(c, end) == '\\' && end + 1u != str::len(c) {
+            alt at(c, end + 1u) {
               '"' {
                 str::push_char(s, '"');
                 end += 2u;
@@ -241,45 +1396,322 @@ fn consume_doubleq(c: [char], offset: uint) -> consumption {
                 str::push_char(s, '\\');
                 end += 2u;
               }
+              '$' | '`' {
+                str::push_char(s, at(c, end + 1u));
+                end += 2u;
+              }
+              // A line continuation, which vanishes.
+              '\n' { end += 2u; }
               _ {
                 str::push_char(s, '\\');
-                str::push_char(s, c[end + 1u]);
-                end += 2u;
+                str::push_char(s, at(c, end + 1u));
+                end = next_char(c, end + 1u);
               }
             }
+        } else if at(c, end) == '$' {
+            let r = consume_dollar(c, end);
+            alt r.t {
+              error(_, _) | continuation(_) { ret r; }
+              _ { }
+            }
+            add_piece(pieces, s, r.t);
+            end = r.offset;
         } else {
-            str::push_char(s, c[end]);
+            str::push_char(s, at(c, end));
+            end = next_char(c, end);
+        }
+    }
+    ret if end == str::len(c) {
+        {t: error("Missing \".", offset), offset: end}
+    } else {
+        {t: finish_word(pieces, s), offset: end + 1u}
+    };
+}
+
+// A word starting with an unquoted ~ and a user name, which is empty for
+// the current user, ends the name at a / or the end of the word. Returns
+// the offset just after the name, or none if the word does not start with
+// such a prefix.
+fn tilde_prefix_end(cfg: tokenizer_config, c: str, offset: uint)
+    -> option<uint> {
+    if offset == str::len(c) || at(c, offset) != '~' {
+        ret none;
+    }
+    let end = offset + 1u;
+    while end < str::len(c) && (is_name_char(at(c, end)) || at(c, end) == '.'
+                                || at(c, end) == '-') {
+        end += 1u;
+    }
+    ret if end == str::len(c) || at(c, end) == '/'
+        || is_token_separator(cfg, c, end) {
+        some(end)
+    } else {
+        none
+    };
+}
+
+// Puts the tilde for user in front of the rest of its word.
+fn add_tilde(user: str, rest: token) -> token {
+    ret alt rest {
+      string(path) { tilde(user, path) }
+      word(pieces) {
+        alt pieces[0u] {
+          string(path) {
+            word([tilde(user, path)]
+                 + vec::slice(pieces, 1u, vec::len(pieces)))
+          }
+          _ { word([tilde(user, "")] + pieces) }
+        }
+      }
+      _ { word([tilde(user, ""), rest]) }
+    };
+}
+
+fn is_glob_char(ch: char) -> bool {
+    ret ch == '*' || ch == '?' || ch == '[' || ch == ']' || ch == '\\';
+}
+
+fn escape_glob(s: str) -> str {
+    let r = "";
+    for ch in str::chars(s) {
+        if is_glob_char(ch) {
+            str::push_char(r, '\\');
+        }
+        str::push_char(r, ch);
+    }
+    ret r;
+}
+
+// An unquoted word is built up one run of text at a time, keeping the
+// text both as written and as a glob pattern, until a variable or other
+// expansion ends the run. A run with an unquoted *, ? or [...] in it
+// becomes a glob piece rather than a string.
+type word_builder = {pieces: [token], mutable text: str,
+                     mutable pattern: str, glob: bool};
+
+fn end_run(b: word_builder) -> [token] {
+    ret if b.glob {
+        b.pieces + [glob(b.pattern)]
+    } else if !str::is_empty(b.text) {
+        b.pieces + [string(b.text)]
+    } else {
+        b.pieces
+    };
+}
+
+// Quoted or escaped text, which never globs.
+fn add_text(&b: word_builder, text: str) {
+    b.text += text;
+    b.pattern += escape_glob(text);
+}
+
+// An unquoted character; is_glob says whether it makes the run a pattern.
+fn add_unquoted(&b: word_builder, ch: char, is_glob: bool) {
+    str::push_char(b.text, ch);
+    str::push_char(b.pattern, ch);
+    if is_glob {
+        b = {glob: true with b};
+    }
+}
+
+fn add_expansion(&b: word_builder, t: token) {
+    alt t {
+      string(text) { add_text(b, text); }
+      word(ps) {
+        for p in ps {
+            add_expansion(b, p);
+        }
+      }
+      _ {
+        b = {pieces: end_run(b) + [t], mutable text: "", mutable pattern: "",
+             glob: false};
+      }
+    }
+}
+
+// Whether the [ at c[offset] starts a bracket expression, i.e. is closed
+// by a ] later in the same word. A ] straight after the [ or [! is part
+// of the set rather than its end.
+fn bracket_closes(cfg: tokenizer_config, c: str, offset: uint) -> bool {
+    let end = offset + 1u;
+    if end < str::len(c) && (at(c, end) == '!' || at(c, end) == '^') {
+        end += 1u;
+    }
+    if end < str::len(c) && at(c, end) == ']' {
+        end += 1u;
+    }
+    while end < str::len(c) && !is_token_separator(cfg, c, end) {
+        if at(c, end) == ']' {
+            ret true;
+        }
+        end = next_char(c, end);
+    }
+    ret false;
+}
+
+// An end of a brace range is an integer or a single letter. Returns
+// whether it is a number, or none if it is neither.
+fn range_end_kind(c: str) -> option<bool> {
+    if str::len(c) == 1u && at(c, 0u) != '_' && is_name_start(at(c, 0u)) {
+        ret some(false);
+    }
+    let start = if str::len(c) > 1u && at(c, 0u) == '-' { 1u } else { 0u };
+    if start == str::len(c) {
+        ret none;
+    }
+    for ch in str::chars(str::slice(c, start, str::len(c))) {
+        if !char::is_digit(ch) {
+            ret none;
+        }
+    }
+    ret some(true);
+}
+
+// Splits the text between a pair of braces at its top-level commas,
+// leaving quoted and nested ones alone.
+fn split_braces(c: str) -> [str] {
+    let items: [str] = [];
+    let item_start = 0u;
+    let depth = 0u;
+    let i = 0u;
+    while i < str::len(c) {
+        alt at(c, i) {
+          '\\' { i += 1u; }
+          '\'' | '"' {
+            let q = at(c, i);
+            i += 1u;
+            while i < str::len(c) && at(c, i) != q {
+                if q == '"' && at(c, i) == '\\' {
+                    i += 1u;
+                }
+                i = next_char(c, i);
+            }
+          }
+          '{' { depth += 1u; }
+          '}' { depth -= 1u; }
+          ',' if depth == 0u {
+            items += [str::slice(c, item_start, i)];
+            item_start = i + 1u;
+          }
+          _ { }
+        }
+        i = next_char(c, i);
+    }
+    ret items + [str::slice(c, item_start, str::len(c))];
+}
+
+// Looks for a brace expansion starting at the { at c[offset]: braces,
+// balanced within the word, around either a top-level comma or a range.
+// Returns the offset of the closing brace and what is between them.
+fn brace_expansion_at(cfg: tokenizer_config, c: str, offset: uint)
+    -> option<{close: uint, body: brace_body}> {
+    let end = offset + 1u;
+    let depth = 0u;
+    while end < str::len(c) && !is_token_separator(cfg, c, end) {
+        alt at(c, end) {
+          '\\' { end += 1u; }
+          '\'' | '"' {
+            let q = at(c, end);
             end += 1u;
+            while end < str::len(c) && at(c, end) != q {
+                if q == '"' && at(c, end) == '\\' {
+                    end += 1u;
+                }
+                end = next_char(c, end);
+            }
+          }
+          '{' { depth += 1u; }
+          '}' {
+            if depth == 0u {
+                break;
+            }
+            depth -= 1u;
+          }
+          _ { }
         }
+        end = next_char(c, end);
+    }
+    if end >= str::len(c) || at(c, end) != '}' {
+        ret none;
+    }
+    let inner = str::slice(c, offset + 1u, end);
+    let items = split_braces(inner);
+    if vec::len(items) > 1u {
+        ret some({close: end, body: brace_list(items)});
+    }
+    let dots = 0u;
+    while dots + 1u < str::len(inner)
+        && !(at(inner, dots) == '.' && at(inner, dots + 1u) == '.') {
+        dots = next_char(inner, dots);
     }
-    ret if end == vec::len(c) {
-        {t: error("Missing \"."), offset: end}
+    if dots + 1u >= str::len(inner) {
+        ret none;
+    }
+    let from = str::slice(inner, 0u, dots);
+    let to = str::slice(inner, dots + 2u, str::len(inner));
+    let kind = range_end_kind(from);
+    ret if kind != none && kind == range_end_kind(to) {
+        some({close: end, body: brace_range(from, to)})
     } else {
-        {t: string(s), offset: end + 1u}
+        none
     };
 }
 
-fn consume_string(c: [char], offset: uint) -> consumption {
-    #debug("consume_string called: '%s', %u", str::from_chars(c), offset);
-    let s: str = "";
+// The offset of the separator ending the word at c[offset]. Quoted
+// strings are stepped over whole, since they may hold separators.
+fn word_end(cfg: tokenizer_config, c: str, offset: uint) -> uint {
+    let end = offset;
+    while end < str::len(c) && !is_token_separator(cfg, c, end) {
+        end = alt at(c, end) {
+          '"' { closing_quote(c, end) + 1u }
+          '\'' {
+            let close = end + 1u;
+            while close < str::len(c) && at(c, close) != '\'' {
+                close += 1u;
+            }
+            close + 1u
+          }
+          '\\' { next_char(c, end + 1u) }
+          _ { next_char(c, end) }
+        };
+    }
+    ret if end > str::len(c) { str::len(c) } else { end };
+}
+
+fn consume_string(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    #debug("consume_string called: '%s', %u", c, offset);
+    let b: word_builder = {pieces: [], mutable text: "", mutable pattern: "",
+                           glob: false};
+    // Enough for the whole word as written, unless it globs a quoted
+    // character, whose escape takes another byte.
+    let bound = word_end(cfg, c, offset) - offset;
+    str::reserve(b.text, bound);
+    str::reserve(b.pattern, bound);
     let end = offset;
-    while end < vec::len(c) {
-        if is_token_separator(c, end) {
+    let user: option<str> = none;
+    alt tilde_prefix_end(cfg, c, offset) {
+      some(name_end) {
+        user = some(str::slice(c, offset + 1u, name_end));
+        end = name_end;
+      }
+      none { }
+    }
+    while end < str::len(c) {
+        if is_token_separator(cfg, c, end) {
             break;
         } else {
-            alt c[end] {
+            alt at(c, end) {
               '"' {
                 let r = consume_doubleq(c, end);
                 alt r {
-                  {t: string(qs), offset: s_offset}  {
-                    s += qs;
-                    end = s_offset;
-                  }
-                  {t: error(_), offset: _} {
+                  {t: error(_, _), offset: _}
+                  | {t: continuation(_), offset: _} {
                     ret r;
                   }
-                  _ {
-                    fail("consume_doubleq returned an unexpected type.");
+                  {t: qt, offset: s_offset} {
+                    add_expansion(b, qt);
+                    end = s_offset;
                   }
                 }
               }
@@ -287,100 +1719,682 @@ fn consume_string(c: [char], offset: uint) -> consumption {
                 let r = consume_singleq(c, end);
                 alt r {
                   {t: string(qs), offset: s_offset}  {
-                    s += qs;
+                    add_text(b, qs);
                     end = s_offset;
                   }
-                  {t: error(_), offset: _} {
+                  {t: error(_, _), offset: _} {
                     ret r;
                   }
                   _ {
-                    fail("consume_doubleq returned an unexpected type.");
+                    fail("consume_singleq returned an unexpected type.");
                   }
                 }
               }
-              _ {
-                str::push_char(s, c[end]);
+              '\\' {
+                // Not at the end of the input, or it would be a separator.
+                if is_line_join(c, end) {
+                    end += 2u;
+                } else {
+                    let escaped = "";
+                    str::push_char(escaped, at(c, end + 1u));
+                    add_text(b, escaped);
+                    end = next_char(c, end + 1u);
+                }
+              }
+              '$' {
+                let r = consume_dollar(c, end);
+                alt r.t {
+                  error(_, _) | continuation(_) { ret r; }
+                  _ { }
+                }
+                add_expansion(b, r.t);
+                end = r.offset;
+              }
+              '*' | '?' {
+                add_unquoted(b, at(c, end), true);
+                end += 1u;
+              }
+              // An unterminated [ is an ordinary character.
+              '[' {
+                if bracket_closes(cfg, c, end) {
+                    add_unquoted(b, '[', true);
+                } else {
+                    add_text(b, "[");
+                }
                 end += 1u;
               }
+              // The rest of the word, which may hold more braces, is
+              // tokenized on its own.
+              '{' {
+                alt brace_expansion_at(cfg, c, end) {
+                  some(e) {
+                    let pre = finish_word(end_run(b), "");
+                    let post = consume_string(cfg, c, e.close + 1u);
+                    alt post.t {
+                      error(_, _) | continuation(_) { ret post; }
+                      _ { }
+                    }
+                    alt user {
+                      some(u) { pre = add_tilde(u, pre); }
+                      none { }
+                    }
+                    ret {t: brace_expand(@pre, e.body, @post.t),
+                         offset: post.offset};
+                  }
+                  none {
+                    add_unquoted(b, '{', false);
+                    end += 1u;
+                  }
+                }
+              }
+              _ {
+                add_unquoted(b, at(c, end), false);
+                end = next_char(c, end);
+              }
             }
         }
     }
-    ret {t: string(s), offset: end};
+    let t = finish_word(end_run(b), "");
+    ret alt user {
+      some(u) { {t: add_tilde(u, t), offset: end} }
+      none { {t: t, offset: end} }
+    };
 }
 
-fn consume_token(c: [char], offset: uint) -> consumption {
-    #debug("consume_token called: '%s', %u", str::from_chars(c), offset);
-    let t: consumption =
-        alt c[offset] {
-          '|' {
-            consume_pipechar(c, offset)
-          }
-          '>' {
-            consume_redirect_output(c, offset)
-          }
-          '<' {
-            consume_redirect_input(c, offset)
-          }
-          '&' {
-            consume_ampersand(c, offset)
-          }
-          '2' {
-            consume_two(c, offset)
-          }
-          ';' {
-            consume_sequence(c, offset)
-          }
-          '(' {
-            consume_open_subshell(c, offset)
-          }
-          ')' {
-            consume_close_subshell(c, offset)
-          }
-          '\\' {
-            if offset + 1u < vec::len(c) {
-                consume_string(c, offset)
-            } else {
-                {t: continuation, offset: offset + 1u}
-            }
-          }
-          _ {
-            consume_string(c, offset)
-          }
-        };
-    let {t:_, offset: end} = consume_whitespace(c, t.offset);
-    ret {t: t.t, offset: end};
+// A here-doc delimiter is never expanded, so a variable in one is kept as
+// written.
+fn consume_literal(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    let r = consume_string(cfg, c, offset);
+    ret alt r.t {
+      error(_, _) | continuation(_) { r }
+      _ { {t: string(word_text(r.t)), offset: r.offset} }
+    };
 }
 
 /*
-Function: tokenize
+Function: word_text
 
-Tokenize a command line
+The text of a word as written, before any expansion
 
 Parameters:
 
-cmd_line - the command line that the user typed; should not be terminated by \n
+t - a word token of any kind: string, variable, parameter,
+    command_subst, arith, tilde, glob, brace_expand or word
 
 Returns:
 
-A vector of tokens
+The word with its quotes removed and each variable spelled $name, or
+${name} where the text after it would otherwise join the name
 */
-fn tokenize(cmd_line: str) -> [token] {
-    let tokens: [token] = [];
-    let c = str::chars(cmd_line);
+fn word_text(t: token) -> str {
+    ret alt t {
+      string(s) { s }
+      variable(n) { "$" + n }
+      parameter(n, op, w) { parameter_text(n, op, word_text(*w)) }
+      command_subst(s) { "$(" + s + ")" }
+      arith(s) { "$((" + s + "))" }
+      tilde(user, path) { "~" + user + path }
+      brace_expand(pre, body, post) {
+        word_text(*pre) + brace_body_to_string(body) + word_text(*post)
+      }
+      glob(p) {
+        let s = "";
+        let escaped = false;
+        for ch in str::chars(p) {
+            if ch == '\\' && !escaped {
+                escaped = true;
+            } else {
+                str::push_char(s, ch);
+                escaped = false;
+            }
+        }
+        s
+      }
+      word(pieces) {
+        let s = "";
+        let i = 0u;
+        while i < vec::len(pieces) {
+            s += alt pieces[i] {
+              variable(n) if runs_on(pieces, i) { "${" + n + "}" }
+              _ { word_text(pieces[i]) }
+            };
+            i += 1u;
+        }
+        s
+      }
+      _ { fail("word_text only takes words."); }
+    };
+}
+
+fn consume_token(cfg: tokenizer_config, c: str, offset: uint)
+    -> consumption {
+    #debug("consume_token called: '%s', %u", c, offset);
+    let ch = at(c, offset);
+    let t: consumption =
+        if vec::contains(cfg.custom_operators, ch) {
+            {t: custom_operator(ch), offset: next_char(c, offset)}
+        } else if char::is_digit(ch) {
+            consume_fd(cfg, c, offset)
+        } else if ch == '\\' {
+            if offset + 1u < str::len(c) {
+                consume_string(cfg, c, offset)
+            } else {
+                {t: continuation(""), offset: offset + 1u}
+            }
+        } else if ch == '\n' {
+            {t: sequence, offset: offset + 1u}
+        } else if !is_operator(cfg, ch) {
+            consume_string(cfg, c, offset)
+        } else {
+            alt ch {
+              '|' {
+                consume_pipechar(c, offset)
+              }
+              '>' {
+                consume_redirect_output(cfg, c, offset)
+              }
+              '<' {
+                consume_redirect_input(cfg, c, offset)
+              }
+              '&' {
+                consume_ampersand(cfg, c, offset)
+              }
+              ';' {
+                consume_sequence(c, offset)
+              }
+              '(' {
+                consume_open_subshell(c, offset)
+              }
+              ')' {
+                consume_close_subshell(c, offset)
+              }
+              _ {
+                fail(#fmt("Unhandled operator character '%c'.", ch));
+              }
+            }
+        };
+    ret t;
+}
 
-    let {t:_, offset} = consume_whitespace(c, 0u);
-    while offset != vec::len(c) {
-        let t = consume_token(c, offset);
-        offset = t.offset;
-        tokens += [t.t];
+// Whether a here-doc line ends in a backslash that is not itself escaped.
+fn joins_next_line(line: str) -> bool {
+    let n = 0u;
+    let i = str::len(line);
+    while i > 0u && line[i - 1u] == '\\' as u8 {
+        n += 1u;
+        i -= 1u;
     }
-    ret tokens;
+    ret n % 2u == 1u;
 }
 
-#[test]
+// A here-doc body starts on the line after its operator, or after the
+// previous here-doc's body when one line has several. The body is cut out
+// of c, so that the rest of the operator's line is tokenized as usual,
+// and the part of c that was removed is added to cuts. token_start is
+// where the operator began and offset where its token ended; a line join
+// in the delimiter may have carried it past the end of the line, in which
+// case offset is moved to just after the cut. The cut is made before the
+// whitespace after the token is skipped, so that is only scanned once,
+// in the text that remains. In an unquoted body a backslash-newline joins
+// two lines, as in sh, and the delimiter is looked for in the joined line;
+// a quoted body is kept as it is. Returns false if the delimiter line
+// never appears, in which case the rest of the input becomes the body.
+fn cut_here_doc_body(&c: str, token_start: uint, &offset: uint,
+                     &doc: here_document, &cuts: [span]) -> bool {
+    let nl = token_start;
+    while nl < str::len(c) && at(c, nl) != '\n' {
+        nl = next_char(c, nl);
+    }
+    if nl == str::len(c) {
+        ret false;
+    }
+    let start = nl + 1u;
+    let line_start = start;
+    let body = "";
+    let rest = str::len(c);
+    while line_start < str::len(c) {
+        let line_end = line_start;
+        while line_end < str::len(c) && at(c, line_end) != '\n' {
+            line_end = next_char(c, line_end);
+        }
+        let text_start = line_start;
+        while doc.strip_tabs && text_start < line_end
+            && at(c, text_start) == '\t' {
+            text_start += 1u;
+        }
+        let line = str::slice(c, text_start, line_end);
+        while !doc.quoted && line_end < str::len(c) && joins_next_line(line) {
+            let next_end = line_end + 1u;
+            while next_end < str::len(c) && at(c, next_end) != '\n' {
+                next_end = next_char(c, next_end);
+            }
+            line = str::slice(line, 0u, str::len(line) - 1u)
+                + str::slice(c, line_end + 1u, next_end);
+            line_end = next_end;
+        }
+        let next = if line_end < str::len(c) { line_end + 1u } else {
+            line_end
+        };
+        if line == doc.delimiter {
+            rest = next;
+            break;
+        }
+        body += line + "\n";
+        line_start = next;
+    }
+    let found = line_start < str::len(c);
+    doc = {body: body with doc};
+    c = str::slice(c, 0u, start) + str::slice(c, rest, str::len(c));
+    cuts += [{start: start, end: rest}];
+    if offset > nl {
+        offset = start;
+    }
+    ret found;
+}
+
+// A '#' at the start of a token begins a comment, which runs to the end
+// of the line. Returns the offset of the next token, or offset itself if
+// there is no comment there.
+fn skip_comment(cfg: tokenizer_config, c: str, offset: uint) -> uint {
+    if offset == str::len(c) || at(c, offset) != '#' {
+        ret offset;
+    }
+    let end = offset;
+    while end < str::len(c) && at(c, end) != '\n' {
+        end = next_char(c, end);
+    }
+    ret consume_whitespace(cfg, c, end).offset;
+}
+
+// Maps an offset into c after the here-doc bodies in cuts were removed
+// back to one into the original command line. An end offset that falls
+// exactly at a cut belongs to the text before it, a start offset to the
+// text after it.
+fn source_offset(cuts: [span], offset: uint, is_end: bool) -> uint {
+    let i = vec::len(cuts);
+    while i > 0u {
+        i -= 1u;
+        let cut = cuts[i];
+        if offset > cut.start || (!is_end && offset == cut.start) {
+            offset += cut.end - cut.start;
+        }
+    }
+    ret offset;
+}
+
+// Converts the byte offsets of a walk through s to character offsets.
+// The offsets only ever move forward, so counter carries on from the last
+// one and the whole walk takes a single pass over s.
+fn char_offset(s: str, &counter: {byte: uint, chars: uint}, byte: uint)
+    -> uint {
+    assert byte >= counter.byte;
+    while counter.byte < byte {
+        counter = {byte: next_char(s, counter.byte),
+                   chars: counter.chars + 1u};
+    }
+    ret counter.chars;
+}
+
+// Whether the next word would be the first word of a command.
+fn at_command_start(tokens: [(token, span)]) -> bool {
+    if vec::is_empty(tokens) {
+        ret true;
+    }
+    let (last, _) = vec::last(tokens);
+    ret alt last {
+      pipe | pipe_both | and | or | background | sequence | open_subshell
+      | negate { true }
+      _ { false }
+    };
+}
+
+/*
+Function: tokenize
+
+Tokenize a command line
+
+Parameters:
+
+cmd_line - the command line that the user typed; newlines separate commands
+
+Returns:
+
+A vector of tokens
+*/
+fn tokenize(cmd_line: str) -> [token] {
+    ret tokenize_with(default_config(), cmd_line);
+}
+
+/*
+Function: tokenize_result
+
+Tokenize a command line, reporting an error apart from the tokens
+
+Parameters:
+
+cmd_line - the command line that the user typed; newlines separate commands
+
+Returns:
+
+left with the tokens <tokenize> would return if none of them is an
+error, or right with the message of the first error token
+*/
+fn tokenize_result(cmd_line: str) -> either<[token], str> {
+    let tokens = tokenize(cmd_line);
+    for t in tokens {
+        alt t {
+          error(e, _) { ret right(e); }
+          _ { }
+        }
+    }
+    ret left(tokens);
+}
+
+/*
+Function: tokenize_with
+
+Tokenize a command line with a custom set of operators and separators
+
+Parameters:
+
+cfg - the characters to treat as operators and separators
+cmd_line - the command line to tokenize; newlines separate commands
+
+Returns:
+
+A vector of tokens. The comments on the variants of token say what each
+one holds.
+
+An unquoted newline is a sequence token, like ;. It adds nothing at
+the start or end of the input, after another separator, or after an
+operator that needs a command to follow (| && || &).
+
+An unquoted ! on its own where a command could start is a negate token.
+Anywhere else it is an ordinary word.
+
+$name, ${...}, $(...) and $((...)) are expansions both unquoted and in
+double quotes. A name is a letter or _, then letters, digits and _. Any
+other $ is literal. ~, globs and braces are expansions only unquoted.
+A word that joins expansions to other text is a word token holding its
+pieces in order.
+
+A # that starts a word begins a comment, and the rest of its line
+produces no tokens. A # inside a word or in quotes is kept.
+
+A continuation token ends the input when more is needed: after a
+trailing \, an unfinished $(( or a here-doc without its delimiter line.
+Read another line, append it to cmd_line after a newline and tokenize
+the whole text again.
+
+An error token holds the character offset in cmd_line where the
+problem was found. That is the opening quote, $( or ${ of an
+unterminated one, or where a missing redirection target should have
+been.
+*/
+fn tokenize_with(cfg: tokenizer_config, cmd_line: str) -> [token] {
+    ret vec::map(tokenize_spans(cfg, cmd_line)) {|p|
+        let (t, _) = p;
+        t
+    };
+}
+
+/*
+Function: tokenize_spans
+
+Tokenize a command line, recording where each token came from
+
+Parameters:
+
+cfg - the characters to treat as operators and separators
+cmd_line - the command line to tokenize
+
+Returns:
+
+The tokens <tokenize_with> would return, each paired with its span in
+cmd_line. A here_doc or fd_here_doc token's span covers the operator and
+delimiter but not the body, which is elsewhere in the input, and a negate
+token's is that of its !. A continuation token added for a missing here-doc
+delimiter has an empty span at the end of the input.
+*/
+fn tokenize_spans(cfg: tokenizer_config, cmd_line: str) -> [(token, span)] {
+    let tokens: [(token, span)] = [];
+    let c = cmd_line;
+    let cuts: [span] = [];
+    let counter = {byte: 0u, chars: 0u};
+    let unterminated = false;
+
+    let newline_last = false;
+
+    let {t:_, offset} = consume_whitespace(cfg, c, 0u);
+    offset = skip_comment(cfg, c, offset);
+    while offset != str::len(c) {
+        let token_start = offset;
+        let t = consume_token(cfg, c, offset);
+        let token_end = t.offset;
+        offset = token_end;
+        let start = char_offset(cmd_line, counter,
+                                source_offset(cuts, token_start, false));
+        let tok = alt t.t {
+          error(msg, pos) {
+            error(msg, char_offset(cmd_line, counter,
+                                   source_offset(cuts, pos, false)))
+          }
+          _ { t.t }
+        };
+        let sp = {start: start,
+                  end: char_offset(cmd_line, counter,
+                                   source_offset(cuts, token_end, true))};
+        alt tok {
+          here_doc(h) {
+            let doc = h;
+            if !cut_here_doc_body(c, token_start, offset, doc, cuts) {
+                unterminated = true;
+            }
+            tokens += [(here_doc(doc), sp)];
+            newline_last = false;
+          }
+          fd_here_doc(fd, h) {
+            let doc = h;
+            if !cut_here_doc_body(c, token_start, offset, doc, cuts) {
+                unterminated = true;
+            }
+            tokens += [(fd_here_doc(fd, doc), sp)];
+            newline_last = false;
+          }
+          sequence if at(c, token_start) == '\n' {
+            if !at_command_start(tokens) {
+                tokens += [(sequence, sp)];
+                newline_last = true;
+            }
+          }
+          // A reserved word written unquoted starts with its own first
+          // character. Of these words, only ! has a token of its own.
+          string(w) if is_reserved_word(w)
+              && at(c, token_start) == str::char_at(w, 0u)
+              && at_command_start(tokens) {
+            tokens += [(if w == "!" { negate } else { tok }, sp)];
+            newline_last = false;
+          }
+          _ {
+            tokens += [(tok, sp)];
+            newline_last = false;
+          }
+        }
+        offset = consume_whitespace(cfg, c, offset).offset;
+        offset = skip_comment(cfg, c, offset);
+    }
+    if newline_last {
+        tokens = vec::slice(tokens, 0u, vec::len(tokens) - 1u);
+    }
+    if unterminated {
+        let n = str::char_len(cmd_line);
+        tokens += [(continuation("heredoc>"), {start: n, end: n})];
+    }
+    ret tokens;
+}
+
+/*
+Type: tokenizer_state
+
+Input fed to <feed> so far, and how many of its tokens have been handed
+out.
+*/
+type tokenizer_state = {text: str, emitted: uint};
+
+/*
+Function: new_state
+
+Start tokenizing input that arrives in pieces, e.g. a line read in
+several reads.
+
+Returns:
+
+A tokenizer_state with no input.
+*/
+fn new_state() -> tokenizer_state {
+    ret {text: "", emitted: 0u};
+}
+
+/*
+Function: feed
+
+Add input to a tokenizer state and take out the tokens it completes.
+
+A token is complete once a character after it shows that more input
+cannot change it, so a word, an open quote or a redirection missing its
+target is held back until it ends, as is a word followed only by a
+backslash. A here-doc and everything after it are held back until its
+delimiter line has been seen. Tokens are those <tokenize> would return
+for all the input fed so far, so a state is meant for one command line
+rather than a whole session.
+
+Parameters:
+
+state - The state; updated to hold the new input.
+chunk - The next piece of input.
+
+Returns:
+
+The newly completed tokens, in order.
+*/
+fn feed(&state: tokenizer_state, chunk: str) -> [token] {
+    let text = state.text + chunk;
+    let tokens = tokenize_spans(default_config(), text);
+    let limit = str::char_len(text);
+    // A here-doc missing its delimiter ends the tokens with an empty
+    // continuation; a trailing backslash, with one of its own.
+    let here_doc_open = false;
+    if vec::is_not_empty(tokens) {
+        alt vec::last(tokens) {
+          (continuation(_), sp) {
+            if sp.start == limit {
+                here_doc_open = true;
+            } else {
+                limit = sp.start;
+            }
+          }
+          _ { }
+        }
+    }
+    let ready: [token] = [];
+    let i = state.emitted;
+    while i < vec::len(tokens) {
+        let (t, sp) = tokens[i];
+        if sp.end >= limit {
+            break;
+        }
+        alt t {
+          here_doc(_) | fd_here_doc(_, _) if here_doc_open { break; }
+          _ { }
+        }
+        ready += [t];
+        i += 1u;
+    }
+    state = {text: text, emitted: i};
+    ret ready;
+}
+
+/*
+Function: finish
+
+Take out the tokens left in a tokenizer state once the input has ended.
+
+Parameters:
+
+state - The state.
+
+Returns:
+
+The tokens that <feed> held back, ending in a continuation or an error
+token if the input stopped in the middle of something. Together with
+what <feed> returned, these are the tokens <tokenize> would return for
+all of the input.
+*/
+fn finish(state: tokenizer_state) -> [token] {
+    let tokens = tokenize(state.text);
+    ret vec::slice(tokens, state.emitted, vec::len(tokens));
+}
+
+/*
+Function: strip_comments
+
+Remove a trailing comment from a command line
+
+A comment starts at a # that begins a word, i.e. at the start of the
+line or after whitespace or an operator. A # inside quotes or in the
+middle of a word is kept.
+
+Parameters:
+
+line - the command line to strip; should not be terminated by \n
+
+Returns:
+
+The part of the line before the comment, or the whole line if it has none
+*/
+fn strip_comments(line: str) -> str {
+    let c = line;
+    let i = 0u;
+    // Where the character before c[i] starts.
+    let prev = 0u;
+    while i < str::len(c) {
+        alt at(c, i) {
+          '\'' {
+            i += 1u;
+            while i < str::len(c) && at(c, i) != '\'' {
+                i = next_char(c, i);
+            }
+          }
+          '"' {
+            i += 1u;
+            while i < str::len(c) && at(c, i) != '"' {
+                if at(c, i) == '\\' && i + 1u < str::len(c) {
+                    i += 1u;
+                }
+                i = next_char(c, i);
+            }
+          }
+          '\\' {
+            i += 1u;
+          }
+          '#' {
+            if i == 0u || is_token_separator(default_config(), c, prev) {
+                ret str::slice(c, 0u, i);
+            }
+          }
+          _ { }
+        }
+        prev = i;
+        i = next_char(c, i);
+    }
+    ret line;
+}
+
+#[test]
 fn simple_cmdline() {
     let ts = tokenize("  hi there");
-    log(info, ts);
+    log(info, debug_string(ts));
     assert ts == [string("hi"), string("there")];
 }
 
@@ -394,44 +2408,1118 @@ fn all_whitespace() {
 fn complex_pipeline() {
     let ts = tokenize("(cat abc d\"e f\\\"\"g; echo 'hello\\') |"
                       + "grep -i he >matches &");
-    log(info, ts);
+    log(info, debug_string(ts));
     assert ts == [open_subshell, string("cat"), string("abc"),
                   string("de f\"g"), sequence, string("echo"),
                   string("hello\\"), close_subshell, pipe, string("grep"),
-                  string("-i"), string("he"), redirect_output("matches"),
-                  background];
+                  string("-i"), string("he"),
+                  redirect_output(@string("matches")), background];
 }
 
 #[test]
 fn test_redirection() {
-    assert tokenize("wc -l < file.txt") == [string("wc"), string("-l"),
-                                            redirect_input("file.txt")];
-    assert tokenize("wc<in>out") == [string("wc"), redirect_input("in"),
-                                     redirect_output("out")];
+    assert tokenize("wc -l < file.txt")
+        == [string("wc"), string("-l"), redirect_input(@string("file.txt"))];
+    assert tokenize("wc<in>out") == [string("wc"),
+                                     redirect_input(@string("in")),
+                                     redirect_output(@string("out"))];
     assert tokenize("wc < >&") ==
-        [string("wc"), error("No input file specified.")];
+        [string("wc"), error("No input file specified.", 5u)];
+}
+
+#[test]
+fn test_append() {
+    assert tokenize("echo hi >> log.txt")
+        == [string("echo"), string("hi"),
+            redirect_output_append(@string("log.txt"))];
+    assert tokenize("foo>>bar") == [string("foo"),
+                                    redirect_output_append(@string("bar"))];
+    assert tokenize("foo > > bar") == [string("foo"),
+                                       error("No output file specified.", 6u)];
+    assert tokenize("foo >>> bar") == [string("foo"),
+                                       error("No output file specified.", 6u)];
+    assert token_to_string(redirect_output_append(@string("a b"))) == ">>'a b'";
 }
 
 #[test]
 fn test_continuation() {
     let ts = tokenize("foo && bar &&\\");
-    log(info, ts);
-    assert ts == [string("foo"), and, string("bar"), and, continuation];
+    log(info, debug_string(ts));
+    assert ts == [string("foo"), and, string("bar"), and, continuation("")];
 }
 
 #[test]
 fn unterminated_string() {
     let ts = tokenize("foo \"bar baz");
-    log(info, ts);
-    assert ts == [string("foo"), error("Missing \".")];
+    log(info, debug_string(ts));
+    assert ts == [string("foo"), error("Missing \".", 4u)];
+    assert tokenize("cat <<EOF\nx\nEOF\necho 'a")
+        == [string("cat"), here_doc({delimiter: "EOF", body: "x\n",
+                                     quoted: false, strip_tabs: false}),
+            sequence, string("echo"), error("Missing '.", 21u)];
 }
 
 #[test]
 fn test_two() {
-    assert tokenize("foo 2>1") == [string("foo"), redirect_error("1")];
+    assert tokenize("foo 2>1") == [string("foo"), redirect_error(@string("1"))];
     assert tokenize("foo 2>&1") == [string("foo"), redirect_error_to_output];
     assert tokenize("foo 2") == [string("foo"), string("2")];
     assert tokenize("foo 2bar") == [string("foo"), string("2bar")];
     assert tokenize("foo 2>&file") == [string("foo"),
-                                       error("No error file specified.")];
+                                       error("No error file specified.", 6u)];
+}
+
+#[test]
+fn test_numbered_fds() {
+    assert tokenize("exec 3>out") == [string("exec"),
+                                      redirect_fd(3u, @string("out"))];
+    assert tokenize("cmd 10<in") == [string("cmd"),
+                                     redirect_fd_input(10u, @string("in"))];
+    assert tokenize("cmd 4< in x") == [string("cmd"),
+                                       redirect_fd_input(4u, @string("in")),
+                                       string("x")];
+    assert tokenize("cmd3>x") == [string("cmd3"),
+                                  redirect_output(@string("x"))];
+    assert tokenize("cmd 1>x 0<y") == [string("cmd"),
+                                       redirect_output(@string("x")),
+                                       redirect_input(@string("y"))];
+    assert tokenize("cmd 02>x") == [string("cmd"),
+                                    redirect_error(@string("x"))];
+    assert tokenize("echo 3 42") == [string("echo"), string("3"),
+                                     string("42")];
+    assert tokenize("cmd 3>") == [string("cmd"),
+                                  error("No output file specified.", 6u)];
+    assert tokenize("cmd 3>>x") == [string("cmd"),
+                                    error("Appending to descriptor 3 is"
+                                          + " not supported.", 4u)];
+    assert token_to_string(redirect_fd_input(5u, @string("a b"))) == "5<'a b'";
+}
+
+#[test]
+fn test_dup_fd() {
+    assert tokenize("cmd 3>&2") == [string("cmd"), dup_fd(3u, 2u)];
+    assert tokenize("cmd 1>&2") == [string("cmd"), dup_fd(1u, 2u)];
+    assert tokenize("cmd >&2") == [string("cmd"), dup_fd(1u, 2u)];
+    assert tokenize("cmd 2>&1") == [string("cmd"), redirect_error_to_output];
+    assert tokenize("cmd 2>&3;x") == [string("cmd"), dup_fd(2u, 3u),
+                                      sequence, string("x")];
+    assert tokenize("cmd 10>&12 x") == [string("cmd"), dup_fd(10u, 12u),
+                                        string("x")];
+    let msg = "Expected a file descriptor after '>&'.";
+    assert tokenize("cmd 3>&x") == [string("cmd"), error(msg, 7u)];
+    assert tokenize("cmd 3>&2x") == [string("cmd"), error(msg, 7u)];
+    assert tokenize("cmd 3>&") == [string("cmd"), error(msg, 7u)];
+    assert token_to_string(dup_fd(3u, 2u)) == "3>&2";
+}
+
+#[test]
+fn test_close_fd() {
+    assert tokenize("cmd 1>&-") == [string("cmd"), close_fd(1u)];
+    assert tokenize("cmd >&-") == [string("cmd"), close_fd(1u)];
+    assert tokenize("ls 2>&- >out") == [string("ls"), close_fd(2u),
+                                        redirect_output(@string("out"))];
+    assert tokenize("cmd 3>&-;x") == [string("cmd"), close_fd(3u), sequence,
+                                      string("x")];
+    assert tokenize("cmd 2>&1") == [string("cmd"), redirect_error_to_output];
+    assert tokenize("cmd 2>&file") == [string("cmd"),
+                                       error("No error file specified.", 6u)];
+    assert tokenize("cmd 3>&-x") == [string("cmd"),
+                                     error("Expected a separator after"
+                                           + " '>&-'.", 8u)];
+    assert token_to_string(close_fd(2u)) == "2>&-";
+}
+
+#[test]
+fn test_here_doc() {
+    fn doc(delimiter: str, body: str, quoted: bool) -> token {
+        ret here_doc({delimiter: delimiter, body: body, quoted: quoted,
+                      strip_tabs: false});
+    }
+    assert tokenize("cat <<EOF\nhello\nworld\nEOF") ==
+        [string("cat"), doc("EOF", "hello\nworld\n", false)];
+    assert tokenize("cat <<EOF | wc -l\nhi\nEOF\necho done") ==
+        [string("cat"), doc("EOF", "hi\n", false), pipe, string("wc"),
+         string("-l"), sequence, string("echo"), string("done")];
+    assert tokenize("cat <<A <<B\na\nA\nb\nB\n") ==
+        [string("cat"), doc("A", "a\n", false), doc("B", "b\n", false)];
+    assert tokenize("cat <<EOF; echo after\none\ntwo\nEOF") ==
+        [string("cat"), doc("EOF", "one\ntwo\n", false), sequence,
+         string("echo"), string("after")];
+    assert tokenize("cat <<EOF & echo after\none\ntwo\nEOF\necho next") ==
+        [string("cat"), doc("EOF", "one\ntwo\n", false), background,
+         string("echo"), string("after"), sequence, string("echo"),
+         string("next")];
+    assert tokenize("cat << 'EOF'\n$x\nEOF") ==
+        [string("cat"), doc("EOF", "$x\n", true)];
+    assert tokenize("cat <<\"EO\"F\n$x\nEOF") ==
+        [string("cat"), doc("EOF", "$x\n", true)];
+    assert tokenize("cat <<E\\OF\n$x\nEOF") ==
+        [string("cat"), doc("EOF", "$x\n", true)];
+    assert tokenize("cat <<EOF\n$x\nEOF") ==
+        [string("cat"), doc("EOF", "$x\n", false)];
+    assert tokenize("cat <<EOF\na\\\nb\nEOF") ==
+        [string("cat"), doc("EOF", "ab\n", false)];
+    assert tokenize("cat <<'EOF'\na\\\nb\nEOF") ==
+        [string("cat"), doc("EOF", "a\\\nb\n", true)];
+    assert tokenize("cat <<EOF\na\\\\\nb\nEOF") ==
+        [string("cat"), doc("EOF", "a\\\\\nb\n", false)];
+    assert tokenize("cat <<EOF\nx\nE\\\nOF\necho") ==
+        [string("cat"), doc("EOF", "x\n", false), sequence, string("echo")];
+    assert tokenize("cat <<EOF\na\\") ==
+        [string("cat"), doc("EOF", "a\\\n", false), continuation("heredoc>")];
+    assert tokenize("cat <<EOF\nhello\n EOF") ==
+        [string("cat"), doc("EOF", "hello\n EOF\n", false),
+         continuation("heredoc>")];
+    assert tokenize("cat <<EOF") ==
+        [string("cat"), doc("EOF", "", false), continuation("heredoc>")];
+    assert tokenize("cat <<") ==
+        [string("cat"), error("No here-doc delimiter specified.", 6u)];
+    assert token_to_string(doc("EOF", "x\n", true)) == "<<'EOF'";
+    assert token_to_string(doc("EOF", "x\n", false)) == "<<EOF";
+}
+
+#[test]
+fn test_here_doc_strip_tabs() {
+    let h = {delimiter: "EOF", body: "hello\n  world\n", quoted: false,
+             strip_tabs: true};
+    assert tokenize("cat <<-EOF\n\thello\n\t  world\n\t\tEOF")
+        == [string("cat"), here_doc(h)];
+    assert tokenize("cat <<- 'EOF'\n\t$x\n\tEOF")
+        == [string("cat"), here_doc({delimiter: "EOF", body: "$x\n",
+                                     quoted: true, strip_tabs: true})];
+    assert tokenize("cat <<-") ==
+        [string("cat"), error("No here-doc delimiter specified.", 7u)];
+    assert token_to_string(here_doc(h)) == "<<-EOF";
+    assert tokenize(detokenize([string("cat"), here_doc(h)]))
+        == [string("cat"), here_doc(h)];
+}
+
+#[test]
+fn test_fd_here_doc() {
+    let h = {delimiter: "EOF", body: "hello\n", quoted: false,
+             strip_tabs: false};
+    assert tokenize("cat 3<<EOF\nhello\nEOF")
+        == [string("cat"), fd_here_doc(3u, h)];
+    assert tokenize("cat 3<<EOF | wc\nhello\nEOF")
+        == [string("cat"), fd_here_doc(3u, h), pipe, string("wc")];
+    assert tokenize("cat 3<<-EOF\n\thello\n\tEOF")
+        == [string("cat"), fd_here_doc(3u, {strip_tabs: true with h})];
+    assert tokenize("cat 0<<EOF\nhello\nEOF") == [string("cat"), here_doc(h)];
+    assert tokenize("cat 3<<EOF")
+        == [string("cat"),
+            fd_here_doc(3u, {body: "" with h}), continuation("heredoc>")];
+    assert tokenize("cat 3<<")
+        == [string("cat"), error("No here-doc delimiter specified.", 7u)];
+    assert token_to_string(fd_here_doc(3u, h)) == "3<<EOF";
+    assert tokenize(detokenize([string("cat"), fd_here_doc(3u, h)]))
+        == [string("cat"), fd_here_doc(3u, h)];
+}
+
+#[test]
+fn test_fd_here_string() {
+    assert tokenize("cmd 4<<<word")
+        == [string("cmd"), fd_here_string(4u, @string("word"))];
+    assert tokenize("cmd 4<<< \"$a b\"")
+        == [string("cmd"),
+            fd_here_string(4u, @word([variable("a"), string(" b")]))];
+    assert tokenize("cmd 4<<<")
+        == [string("cmd"), error("No here-string specified.", 8u)];
+    assert tokenize("cmd 4<in") == [string("cmd"),
+                                    redirect_fd_input(4u, @string("in"))];
+    assert token_to_string(fd_here_string(4u, @string("a b")))
+        == "4<<<'a b'";
+}
+
+#[test]
+fn test_here_string() {
+    assert tokenize("grep foo <<< \"$bar baz\"") ==
+        [string("grep"), string("foo"),
+         here_string(@word([variable("bar"), string(" baz")]))];
+    assert tokenize("cat <<<word|wc") ==
+        [string("cat"), here_string(@string("word")), pipe, string("wc")];
+    assert tokenize("cat <<< ''") == [string("cat"), here_string(@string(""))];
+    assert tokenize("cat <<<") ==
+        [string("cat"), error("No here-string specified.", 7u)];
+    assert token_to_string(here_string(@string("a b"))) == "<<<'a b'";
+}
+
+#[test]
+fn test_two_append() {
+    assert tokenize("foo 2>> errors.log") == [string("foo"),
+                                              redirect_error_append(
+                                                  @string("errors.log"))];
+    assert tokenize("foo 2>>e 2>f") == [string("foo"),
+                                        redirect_error_append(@string("e")),
+                                        redirect_error(@string("f"))];
+    assert tokenize("foo 2>>&1") == [string("foo"),
+                                     error("Cannot append to a file"
+                                           + " descriptor.", 7u)];
+    assert tokenize("foo 2>>") == [string("foo"),
+                                   error("No error file specified.", 7u)];
+}
+
+#[test]
+fn test_tokenize_result() {
+    assert tokenize_result("ls -l | wc")
+        == left([string("ls"), string("-l"), pipe, string("wc")]);
+    assert tokenize_result("") == left([]);
+    assert tokenize_result("echo \"a b") == right("Missing \".");
+    assert tokenize_result("ls > >x") == right("No output file specified.");
+}
+
+#[test]
+fn test_debug_string() {
+    assert debug_string([]) == "";
+    assert debug_string([string("ls"), string("a \"b\""), pipe,
+                         redirect_output(@string("out")),
+                         redirect_error_to_output,
+                         error("Missing '.", 3u)])
+        == "string(\"ls\")\n"
+         + "string(\"a \\\"b\\\"\")\n"
+         + "pipe\n"
+         + "redirect_output(string(\"out\"))\n"
+         + "redirect_error_to_output\n"
+         + "error(\"Missing '.\", 3)\n";
+}
+
+#[test]
+fn test_ampersand_redirect() {
+    assert tokenize("a & >b") == [string("a"), background,
+                                  redirect_output(@string("b"))];
+    assert tokenize("a &&>b") == [string("a"), and,
+                                  redirect_output(@string("b"))];
+    assert tokenize("a &>b") == [string("a"), redirect_both(@string("b"))];
+    assert tokenize("a &> b c") == [string("a"), redirect_both(@string("b")),
+                                    string("c")];
+    assert tokenize("a &>") == [string("a"),
+                                error("No output file specified.", 4u)];
+}
+
+#[test]
+fn test_redirect_both_append() {
+    assert tokenize("a &>>log") == [string("a"),
+                                    redirect_both_append(@string("log"))];
+    assert tokenize("a &>> log b") == [string("a"),
+                                       redirect_both_append(@string("log")),
+                                       string("b")];
+    assert tokenize("a &>f >g") == [string("a"), redirect_both(@string("f")),
+                                    redirect_output(@string("g"))];
+    assert tokenize("a &> >g") == [string("a"),
+                                   error("No output file specified.", 5u)];
+    assert tokenize("a &>>") == [string("a"),
+                                 error("No output file specified.", 5u)];
+}
+
+#[test]
+fn test_strip_comments() {
+    assert strip_comments("ls -l   # list files") == "ls -l   ";
+    assert strip_comments("# just a comment") == "";
+    assert strip_comments("echo \"# not\" '# this' # but this")
+        == "echo \"# not\" '# this' ";
+    assert strip_comments("echo \"a\\\"#b\"") == "echo \"a\\\"#b\"";
+    assert strip_comments("echo foo#bar") == "echo foo#bar";
+    assert strip_comments("a;#b") == "a;";
+}
+
+#[test]
+fn test_comments() {
+    assert tokenize("ls -l   # list files") == [string("ls"), string("-l")];
+    assert tokenize("# just a comment") == [];
+    assert tokenize("echo file#1") == [string("echo"), string("file#1")];
+    assert tokenize("echo \"#\" '#x' \\#y") == [string("echo"),
+                                                string("#"), string("#x"),
+                                                string("#y")];
+    assert tokenize("a;#b") == [string("a"), sequence];
+    assert tokenize("a # b\nc") == [string("a"), sequence, string("c")];
+    assert tokenize("cat <<EOF # x\n# body\nEOF") ==
+        [string("cat"), here_doc({delimiter: "EOF", body: "# body\n",
+                                  quoted: false, strip_tabs: false})];
+}
+
+#[test]
+fn test_unicode_spaces() {
+    assert tokenize("a\u00a0b") == [string("a\u00a0b")];
+    assert tokenize("a\u3000b c") == [string("a\u3000b"), string("c")];
+    assert tokenize("a\tb\x0bc\x0cd\re") == [string("a"), string("b"),
+                                                string("c"), string("d"),
+                                                string("e")];
+    assert !is_metacharacter('\u00a0');
+    assert quote("a\u00a0b") == "a\u00a0b";
+}
+
+#[test]
+fn test_newline_sequence() {
+    assert tokenize("echo a\necho b") == [string("echo"), string("a"),
+                                          sequence, string("echo"),
+                                          string("b")];
+    assert tokenize("echo a\n") == [string("echo"), string("a")];
+    assert tokenize("\n\na\n\n\nb\n\n") == [string("a"), sequence,
+                                            string("b")];
+    assert tokenize("a;\nb") == [string("a"), sequence, string("b")];
+    assert tokenize("a |\nb") == [string("a"), pipe, string("b")];
+    assert tokenize("a &&\nb &\nc") == [string("a"), and, string("b"),
+                                        background, string("c")];
+    assert tokenize("(a\nb\n)") == [open_subshell, string("a"), sequence,
+                                    string("b"), sequence, close_subshell];
+    assert tokenize("echo 'a\nb' \\\nc") == [string("echo"),
+                                             string("a\nb"), string("c")];
+    assert tokenize("a>out\nb") == [string("a"),
+                                    redirect_output(@string("out")),
+                                    sequence, string("b")];
+}
+
+#[test]
+fn test_pipe_both() {
+    assert tokenize("make |& less") == [string("make"), pipe_both,
+                                        string("less")];
+    assert tokenize("a|&b") == [string("a"), pipe_both, string("b")];
+    assert tokenize("a | &b") == [string("a"), pipe, background,
+                                  string("b")];
+    assert tokenize("a ||&b") == [string("a"), or, background, string("b")];
+    assert tokenize("a |&\nb") == [string("a"), pipe_both, string("b")];
+}
+
+#[test]
+fn test_negate() {
+    assert tokenize("! false") == [negate, string("false")];
+    assert tokenize("echo a!b") == [string("echo"), string("a!b")];
+    assert tokenize("echo !") == [string("echo"), string("!")];
+    assert tokenize("echo '!' \\!") == [string("echo"), string("!"),
+                                        string("!")];
+    assert tokenize("!x") == [string("!x")];
+    assert tokenize("a && ! b | c") == [string("a"), and, negate,
+                                        string("b"), pipe, string("c")];
+    assert tokenize("(! a)\n! b") == [open_subshell, negate, string("a"),
+                                      close_subshell, sequence, negate,
+                                      string("b")];
+    assert tokenize("'!' x") == [string("!"), string("x")];
+}
+
+#[test]
+fn test_variable() {
+    assert tokenize("echo $HOME") == [string("echo"), variable("HOME")];
+    assert tokenize("echo \"$HOME/bin\"")
+        == [string("echo"), word([variable("HOME"), string("/bin")])];
+    assert tokenize("echo $") == [string("echo"), string("$")];
+    assert tokenize("echo $1 $- a$ \"$\"")
+        == [string("echo"), string("$1"), string("$-"), string("a$"),
+            string("$")];
+    assert tokenize("echo '$HOME' \\$HOME \"\\$HOME\"")
+        == [string("echo"), string("$HOME"), string("$HOME"),
+            string("$HOME")];
+    assert tokenize("a$B_1.c") == [word([string("a"), variable("B_1"),
+                                         string(".c")])];
+    assert tokenize("$A\"x $B\"") == [word([variable("A"), string("x "),
+                                             variable("B")])];
+    assert tokenize("cmd > $LOG <<$END\n$x\n$END")
+        == [string("cmd"), redirect_output(@variable("LOG")),
+            here_doc({delimiter: "$END", body: "$x\n", quoted: false,
+                      strip_tabs: false})];
+    assert tokenize("cmd > ${LOG}_1 <<$END\n$x\n$END")
+        == [string("cmd"),
+            redirect_output(@word([variable("LOG"), string("_1")])),
+            here_doc({delimiter: "$END", body: "$x\n", quoted: false,
+                      strip_tabs: false})];
+    assert tokenize("cmd >'$x' >$x 2>\"$d\"/e <<< '$a' <<< $a")
+        == [string("cmd"), redirect_output(@string("$x")),
+            redirect_output(@variable("x")),
+            redirect_error(@word([variable("d"), string("/e")])),
+            here_string(@string("$a")), here_string(@variable("a"))];
+    for t in [redirect_output(@string("$x")), redirect_input(@variable("x")),
+              here_string(@word([variable("a"), string(" b")])),
+              redirect_fd(3u, @word([variable("x"), string(".log")]))] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+
+    for t in [variable("A"), word([variable("A"), string("b")]),
+              word([variable("A"), variable("B"), string("_")]),
+              word([string("a b"), variable("A"), string("-")]),
+              string("it's $A")] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+}
+
+#[test]
+fn test_tokenize_with() {
+    let cfg = {operators: ['<', '>', ';', '&', '(', ')'], separators: [','],
+               custom_operators: []};
+    assert tokenize_with(cfg, "a,b , c") == [string("a"), string("b"),
+                                             string("c")];
+    assert tokenize_with(cfg, "a|b | c") == [string("a|b"), string("|"),
+                                             string("c")];
+    assert tokenize_with(cfg, "a \",\" >out")
+        == [string("a"), string(","), redirect_output(@string("out"))];
+    assert tokenize_with(default_config(), "a,b | c")
+        == tokenize("a,b | c");
+
+    let no_redirects = {operators: [';'], separators: [],
+                        custom_operators: []};
+    assert tokenize_with(no_redirects, "a 2>b; c>d")
+        == [string("a"), string("2>b"), sequence, string("c>d")];
+}
+
+#[test]
+fn test_custom_operators() {
+    let cfg = {custom_operators: ['=', ':', '|'] with default_config()};
+    assert tokenize_with(cfg, "a=b:c")
+        == [string("a"), custom_operator('='), string("b"),
+            custom_operator(':'), string("c")];
+    assert tokenize_with(cfg, "x = 'a=b' \\= \":\"")
+        == [string("x"), custom_operator('='), string("a=b"), string("="),
+            string(":")];
+    assert tokenize_with(cfg, "a|b; c>d")
+        == [string("a"), custom_operator('|'), string("b"), sequence,
+            string("c"), redirect_output(@string("d"))];
+    assert tokenize_with(cfg, "a==b")
+        == [string("a"), custom_operator('='), custom_operator('='),
+            string("b")];
+    assert tokenize("a=b:c") == [string("a=b:c")];
+    assert token_to_string(custom_operator('=')) == "=";
+    assert token_to_debug_string(custom_operator('='))
+        == "custom_operator(\"=\")";
+}
+
+#[test]
+fn test_token_equality() {
+    let ts = [string("a"), string("b"), pipe, pipe_both,
+              redirect_output(@string("a")),
+              redirect_output_append(@string("a")),
+              redirect_error(@string("a")),
+              redirect_error_append(@string("a")), redirect_error_to_output,
+              redirect_both(@string("a")), redirect_both_append(@string("a")),
+              redirect_input(@string("a")), redirect_fd(3u, @string("a")),
+              redirect_fd_input(3u, @string("a")), dup_fd(3u, 2u),
+              close_fd(3u),
+              here_doc({delimiter: "a", body: "", quoted: false,
+                        strip_tabs: false}),
+              variable("a"), word([string("a"), variable("b")]),
+              parameter("a", use_default, @string("b")), command_subst("a"),
+              arith("a"), tilde("a", "/b"), glob("*"),
+              brace_expand(@string("a"), brace_list(["b", "c"]),
+                           @string("")),
+              here_string(@string("a")), and, or, negate,
+              background, sequence, open_subshell, close_subshell,
+              custom_operator(','), continuation(""), error("a", 0u)];
+    let i = 0u;
+    while i < vec::len(ts) {
+        let j = 0u;
+        while j < vec::len(ts) {
+            assert (ts[i] == ts[j]) == (i == j);
+            assert (ts[i] != ts[j]) == (i != j);
+            j += 1u;
+        }
+        i += 1u;
+    }
+}
+
+#[test]
+fn test_token_ordering() {
+    let sorted = std::sort::merge_sort({|a: token, b: token| a <= b},
+                                       [close_subshell, string("b"), pipe,
+                                        redirect_input(@string("x")),
+                                        string("a")]);
+    assert sorted == [string("a"), string("b"), pipe,
+                      redirect_input(@string("x")), close_subshell];
+    assert string("a") < string("b");
+    assert pipe > string("z");
+}
+
+#[test]
+fn test_line_join() {
+    assert tokenize("\\") == [continuation("")];
+    assert tokenize("echo \\\nhi") == [string("echo"), string("hi")];
+    assert tokenize("echo a\\\nb") == [string("echo"), string("ab")];
+    assert tokenize("echo \\\n") == [string("echo")];
+    assert tokenize("\\\n") == [];
+}
+
+#[test]
+fn test_quote() {
+    assert !needs_quoting("ls");
+    assert !needs_quoting("a=b,c.d/e~f#g");
+    assert needs_quoting("");
+    assert needs_quoting("a b");
+    assert needs_quoting("*.txt");
+    assert needs_quoting("~user");
+    assert needs_quoting("#x");
+    assert quote("ls") == "ls";
+    assert quote("") == "''";
+    assert quote("a b") == "'a b'";
+    assert quote("*.txt") == "'*.txt'";
+    assert quote("it's") == "\"it's\"";
+    assert quote("say \"it's\" \\o/") == "\"say \\\"it's\\\" \\\\o/\"";
+    assert quote("it's `date`") == "\"it's \\`date\\`\"";
+
+    for w in ["ls", "", "a b", "*.txt", "it's", "say \"it's\" \\o/",
+              "x|y", "2>&1", "a\\", "it's `date` $x"] {
+        assert tokenize(quote(w)) == [string(w)];
+    }
+    assert token_to_string(redirect_output(@string("my file"))) == ">'my file'";
+}
+
+#[test]
+fn test_backslash_escape() {
+    assert tokenize("echo \\n") == [string("echo"), string("n")];
+    assert tokenize("echo \\\\n") == [string("echo"), string("\\n")];
+    assert tokenize("echo \\") == [string("echo"), continuation("")];
+    assert tokenize("echo n\\") == [string("echo"), string("n"),
+                                    continuation("")];
+    assert strip_comments("echo it\\'s # c") == "echo it\\'s ";
+    assert tokenize("cat foo\\ bar a\\;b a\\|b \\&\\>x")
+        == [string("cat"), string("foo bar"), string("a;b"), string("a|b"),
+            string("&>x")];
+    assert tokenize("echo a\\ ") == [string("echo"), string("a ")];
+    assert tokenize("echo a\\ \\") == [string("echo"), string("a "),
+                                       continuation("")];
+}
+
+#[test]
+fn test_reserved_words() {
+    for w in ["if", "then", "else", "elif", "fi", "do", "done", "case",
+              "esac", "while", "until", "for", "in", "function", "select",
+              "time", "{", "}", "!", "[[", "]]"] {
+        assert is_reserved_word(w);
+    }
+    for w in ["echo", "cd", "", "If", "fi;", "[", "]", "test"] {
+        assert !is_reserved_word(w);
+    }
+    assert tokenize("! if") == [negate, string("if")];
+    assert tokenize("if ! x") == [string("if"), string("!"), string("x")];
+}
+
+#[test]
+fn test_spans() {
+    fn spans(cmd_line: str) -> [(uint, uint)] {
+        ret vec::map(tokenize_spans(default_config(), cmd_line)) {|p|
+            let (_, sp) = p;
+            (sp.start, sp.end)
+        };
+    }
+    assert tokenize_spans(default_config(), "  ls -l|wc  ")
+        == [(string("ls"), {start: 2u, end: 4u}),
+            (string("-l"), {start: 5u, end: 7u}),
+            (pipe, {start: 7u, end: 8u}),
+            (string("wc"), {start: 8u, end: 10u})];
+    assert spans("a\\  b # c") == [(0u, 3u), (4u, 5u)];
+    assert spans("a \\\nb") == [(0u, 1u), (4u, 5u)];
+    assert spans("! a > out") == [(0u, 1u), (2u, 3u), (4u, 9u)];
+    assert spans("echo \"a b\"$x;") == [(0u, 4u), (5u, 12u), (12u, 13u)];
+    assert spans("cat <<EOF; ls\nhi\nEOF\necho x")
+        == [(0u, 3u), (4u, 9u), (9u, 10u), (11u, 13u), (13u, 14u),
+            (21u, 25u), (26u, 27u)];
+    assert vec::last(tokenize_spans(default_config(), "cat <<EOF\nhi"))
+        == (continuation("heredoc>"), {start: 12u, end: 12u});
+    assert spans("\u00e9t\u00e9 | wc") == [(0u, 3u), (4u, 5u), (6u, 8u)];
+    assert tokenize("a\u00e9 '\u00e9") == [string("a\u00e9"),
+                                            error("Missing '.", 3u)];
+}
+
+#[test]
+fn test_feed() {
+    let state = new_state();
+    assert feed(state, "ec") == [];
+    assert feed(state, "ho hi") == [string("echo")];
+    assert finish(state) == [string("hi")];
+
+    state = new_state();
+    assert feed(state, "echo 'a") == [string("echo")];
+    assert feed(state, " b' |") == [string("a b")];
+    assert finish(state) == [pipe];
+
+    state = new_state();
+    assert feed(state, "cat <<EOF\nhi\n") == [string("cat")];
+    assert feed(state, "EOF\nwc")
+        == [here_doc({delimiter: "EOF", body: "hi\n", quoted: false,
+                      strip_tabs: false}),
+            sequence];
+    assert finish(state) == [string("wc")];
+
+    state = new_state();
+    assert feed(state, "a\\") == [];
+    assert feed(state, "b c") == [string("ab")];
+    assert finish(state) == [string("c")];
+
+    // However the input is split, the tokens come out the same.
+    for cmd_line in ["echo hi", "ls -l | wc\n", "a && b || c &", "a\\ b",
+                     "cat <<EOF; ls\nhi\nEOF\necho x", "x \\\ny",
+                     "echo \"$HOME\" ${x:-a b} $(date)", "a 2>&1 >out"] {
+        let c = str::chars(cmd_line);
+        let i = 0u;
+        while i <= vec::len(c) {
+            let split = new_state();
+            let ts = feed(split, str::from_chars(vec::slice(c, 0u, i)));
+            ts += feed(split, str::from_chars(vec::slice(c, i, vec::len(c))));
+            ts += finish(split);
+            assert ts == tokenize(cmd_line);
+            i += 1u;
+        }
+    }
+}
+
+// Walks the input the way tokenize_with does, checking that every token
+// moves the offset forward and stays within the input, for the seed
+// corpus below and for pseudo-random inputs built from the characters the
+// consume_* functions special-case. Any failure in their offset
+// arithmetic shows up as an out-of-bounds index or a failed assert. The
+// spans tokenize_spans records must also be in order and within the
+// input.
+#[test]
+fn test_tokenize_invariants() {
+    // Offsets must stay at the start of a character, never on one of the
+    // continuation bytes 0x80 to 0xbf of a longer UTF-8 sequence.
+    fn on_boundary(c: str, offset: uint) -> bool {
+        ret offset == str::len(c) || c[offset] < 128u8 || c[offset] >= 192u8;
+    }
+    fn check(cmd_line: str) {
+        let cfg = default_config();
+        let c = cmd_line;
+        let {t:_, offset} = consume_whitespace(cfg, c, 0u);
+        offset = skip_comment(cfg, c, offset);
+        assert offset <= str::len(c);
+        let cuts: [span] = [];
+        while offset != str::len(c) {
+            assert on_boundary(c, offset);
+            // The whitespace before a token has already been skipped.
+            assert consume_whitespace(cfg, c, offset).offset == offset;
+            let token_start = offset;
+            let t = consume_token(cfg, c, offset);
+            assert t.offset > offset;
+            assert t.offset <= str::len(c);
+            assert on_boundary(c, t.offset);
+            offset = t.offset;
+            alt t.t {
+              here_doc(h) {
+                let doc = h;
+                cut_here_doc_body(c, token_start, offset, doc, cuts);
+                assert offset <= str::len(c);
+              }
+              _ { }
+            }
+            let ws_end = consume_whitespace(cfg, c, offset).offset;
+            assert ws_end >= offset;
+            offset = skip_comment(cfg, c, ws_end);
+            assert offset >= ws_end;
+            assert offset <= str::len(c);
+        }
+        assert offset == str::len(c);
+        let last_end = 0u;
+        for p in tokenize_spans(cfg, cmd_line) {
+            let (_, sp) = p;
+            assert last_end <= sp.start;
+            assert sp.start <= sp.end;
+            assert sp.end <= str::char_len(cmd_line);
+            last_end = sp.end;
+        }
+        strip_comments(cmd_line);
+    }
+    let corpus = ["", "ls", "ls -l | grep foo > out", "foo 2>&1", "foo 2>",
+                  "a && b || (c; d) &", "echo 'unterminated",
+                  "echo \"a\\\"b\"", "cat <<EOF\nhi\nEOF", "cat <<",
+                  "a &>>log", "exec 3>&- 4<in 5>&2", "x \\", "x \\\ny",
+                  "wc < >&", "a ;# comment", "2>>&1", "1>&x",
+                  "echo \"$HOME/bin\" $", "a$B$", "ls *.txt f[1-3] a[",
+                  "ls [!]*", "a{b,c{d}}e {1..2 {'}'",
+                  "echo h\u00e9 '\u00e9' \"\u00e9$x\" \\\u00e9 #\u00e9",
+                  "cat <<\u00c9\n\u00e9\n\u00c9\n$(\u00e9) ${x:-\u00df}\u00e9"];
+    for cmd_line in corpus {
+        check(cmd_line);
+    }
+    let alphabet = ['a', '0', '1', '2', '3', '-', '>', '<', '&', '|', ';',
+                    '(', ')', '\'', '"', '\\', ' ', '\n', '#', 'E',
+                    '$', '\u00e9', '{', ','];
+    let seed = 12345u;
+    let n = 0u;
+    while n < 2000u {
+        let cmd_line = "";
+        let len = n % 13u;
+        let i = 0u;
+        while i < len {
+            seed = (seed * 1103515245u + 12345u) % 2147483648u;
+            str::push_char(cmd_line,
+                           alphabet[(seed >> 16u) % vec::len(alphabet)]);
+            i += 1u;
+        }
+        check(cmd_line);
+        n += 1u;
+    }
+}
+
+#[test]
+fn test_braced_variable() {
+    assert tokenize("echo ${PATH}") == [string("echo"), variable("PATH")];
+    assert tokenize("echo ${foo}bar \"${foo}bar\"")
+        == [string("echo"), word([variable("foo"), string("bar")]),
+            word([variable("foo"), string("bar")])];
+    assert tokenize("echo ${PATH") == [string("echo"), error("Missing }.", 5u)];
+    assert tokenize("echo \"${PATH\"")
+        == [string("echo"), error("Missing }.", 6u)];
+    assert tokenize("echo ${} ${1x} ${a b}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo '${PATH'") == [string("echo"), string("${PATH")];
+    assert token_to_string(word([variable("foo"), string("bar")]))
+        == "${foo}bar";
+}
+
+// Nothing escapes a single quote inside single quotes, backslash included.
+#[test]
+fn test_single_quote_backslash() {
+    assert tokenize("echo 'a\\'") == [string("echo"), string("a\\")];
+    assert tokenize("echo 'a\\\\'") == [string("echo"), string("a\\\\")];
+    assert tokenize("echo 'a\\'b'")
+        == [string("echo"), error("Missing '.", 10u)];
+    assert tokenize("echo 'a\\'b") == [string("echo"), string("a\\b")];
+    assert tokenize("echo 'it'\\''s'") == [string("echo"), string("it's")];
+}
+
+#[test]
+fn test_parameter_ops() {
+    assert tokenize("echo ${x:-default}")
+        == [string("echo"), parameter("x", use_default, @string("default"))];
+    assert tokenize("echo ${x:=a b}")
+        == [string("echo"), parameter("x", assign_default, @string("a b"))];
+    assert tokenize("echo ${x:?\"not set; stop\"}")
+        == [string("echo"),
+            parameter("x", error_if_unset, @string("not set; stop"))];
+    assert tokenize("echo ${x:+$y/bin}")
+        == [string("echo"),
+            parameter("x", use_alternate,
+                      @word([variable("y"), string("/bin")]))];
+    assert tokenize("a${x:-}b")
+        == [word([string("a"), parameter("x", use_default, @string("")),
+                  string("b")])];
+    assert tokenize("echo ${x:-${y:-'}'}}")
+        == [string("echo"),
+            parameter("x", use_default,
+                      @parameter("y", use_default, @string("}")))];
+    assert tokenize("echo ${x}") == [string("echo"), variable("x")];
+    assert tokenize("echo ${x:%y}")
+        == [string("echo"), error("Unknown expansion operator ':%'.", 5u)];
+    assert tokenize("echo ${x-y}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo ${x:-\"y}")
+        == [string("echo"), error("Missing \".", 10u)];
+
+    for t in [parameter("x", use_default, @string("a b")),
+              parameter("x", error_if_unset, @string("}")),
+              parameter("x", use_alternate,
+                        @word([variable("y"), string("/bin")]))] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+}
+
+#[test]
+fn test_transform_parameter() {
+    assert tokenize("echo ${x@Q}")
+        == [string("echo"), parameter("x", transform('Q'), @string(""))];
+    assert tokenize("echo \"${x@U}\"")
+        == [string("echo"), parameter("x", transform('U'), @string(""))];
+    assert tokenize("echo ${x@E}${x@P}${x@L}${x@u}${x@l}")
+        == [string("echo"),
+            word([parameter("x", transform('E'), @string("")),
+                  parameter("x", transform('P'), @string("")),
+                  parameter("x", transform('L'), @string("")),
+                  parameter("x", transform('u'), @string("")),
+                  parameter("x", transform('l'), @string(""))])];
+    assert tokenize("echo ${x@Z}")
+        == [string("echo"),
+            error("Unknown transformation operator '@Z'.", 5u)];
+    assert tokenize("echo ${x@}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo ${x@QQ}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo ${x@Q")
+        == [string("echo"), error("Missing }.", 5u)];
+
+    for ch in ['Q', 'E', 'P', 'U', 'L', 'u', 'l'] {
+        let t = parameter("x", transform(ch), @string(""));
+        assert tokenize(token_to_string(t)) == [t];
+        assert word_text(t) == "${x@" + str::from_char(ch) + "}";
+    }
+}
+
+#[test]
+fn test_case_parameter() {
+    assert tokenize("echo ${x^^}")
+        == [string("echo"), parameter("x", upper_all, @string(""))];
+    assert tokenize("echo ${x,,}")
+        == [string("echo"), parameter("x", lower_all, @string(""))];
+    assert tokenize("echo ${x^}${x,}")
+        == [string("echo"),
+            word([parameter("x", upper_first, @string("")),
+                  parameter("x", lower_first, @string(""))])];
+    assert tokenize("echo ${x^[aeiou]}")
+        == [string("echo"), parameter("x", upper_first, @string("[aeiou]"))];
+    assert tokenize("echo ${x,,$p}")
+        == [string("echo"), parameter("x", lower_all, @variable("p"))];
+    assert tokenize("echo ${x^^[a-z]")
+        == [string("echo"), error("Missing }.", 5u)];
+
+    for t in [parameter("x", upper_all, @string("")),
+              parameter("x", lower_first, @string("")),
+              parameter("x", upper_first, @string("[aeiou]")),
+              parameter("x", lower_all, @string("a b"))] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+    assert word_text(parameter("x", upper_first, @string("[aeiou]")))
+        == "${x^[aeiou]}";
+}
+
+#[test]
+fn test_indirect_parameter() {
+    assert tokenize("echo ${!x}")
+        == [string("echo"), parameter("x", indirect, @string(""))];
+    assert tokenize("echo ${!arr[@]}")
+        == [string("echo"), parameter("arr", array_keys('@'), @string(""))];
+    assert tokenize("echo \"${!arr[*]}\"")
+        == [string("echo"), parameter("arr", array_keys('*'), @string(""))];
+    assert tokenize("a${!x}b")
+        == [word([string("a"), parameter("x", indirect, @string("")),
+                  string("b")])];
+    assert tokenize("echo ${!}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo ${!x:-y}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo ${!arr[0]}")
+        == [string("echo"), error("Bad substitution.", 5u)];
+    assert tokenize("echo ${!x")
+        == [string("echo"), error("Missing }.", 5u)];
+    assert token_to_debug_string(parameter("x", indirect, @string("")))
+        == "parameter(\"x\", \"!\", string(\"\"))";
+
+    for t in [parameter("x", indirect, @string("")),
+              parameter("arr", array_keys('@'), @string("")),
+              parameter("arr", array_keys('*'), @string(""))] {
+        assert tokenize(token_to_string(t)) == [t];
+        assert word_text(t) == token_to_string(t);
+    }
+}
+
+#[test]
+fn test_command_subst() {
+    assert tokenize("echo $(date)") == [string("echo"), command_subst("date")];
+    assert tokenize("echo $(echo $(date))")
+        == [string("echo"), command_subst("echo $(date)")];
+    assert tokenize("echo \"today: $(date +%A)\"")
+        == [string("echo"),
+            word([string("today: "), command_subst("date +%A")])];
+    assert tokenize("echo $(echo ')' \"(\" \\)) x")
+        == [string("echo"), command_subst("echo ')' \"(\" \\)"),
+            string("x")];
+    assert tokenize("echo $(ls; pwd)") == [string("echo"),
+                                           command_subst("ls; pwd")];
+    assert tokenize("echo $(date") == [string("echo"), error("Missing ).", 5u)];
+    assert tokenize("echo $(echo $(date)")
+        == [string("echo"), error("Missing ).", 5u)];
+    assert tokenize("echo '$(date)'") == [string("echo"), string("$(date)")];
+    assert tokenize(token_to_string(command_subst("echo $(date)")))
+        == [command_subst("echo $(date)")];
+}
+
+#[test]
+fn test_arith() {
+    assert tokenize("echo $(( 1 + 2 ))") == [string("echo"),
+                                             arith(" 1 + 2 ")];
+    assert tokenize("echo $(( (1+2) * 3 ))")
+        == [string("echo"), arith(" (1+2) * 3 ")];
+    assert tokenize("echo \"$((x*2))s\"")
+        == [string("echo"), word([arith("x*2"), string("s")])];
+    assert tokenize("echo $( (cd x; pwd) )")
+        == [string("echo"), command_subst(" (cd x; pwd) ")];
+    assert tokenize("echo $((cd x; pwd) )")
+        == [string("echo"), command_subst("(cd x; pwd) ")];
+    assert tokenize("echo $((1 + 2") == [string("echo"),
+                                         continuation("arith>")];
+    assert tokenize("echo a$((1 +") == [string("echo"),
+                                        continuation("arith>")];
+    assert tokenize("echo \"$((1 +") == [string("echo"),
+                                          continuation("arith>")];
+    assert tokenize("cat >$((1 +") == [string("cat"),
+                                       continuation("arith>")];
+    assert tokenize("echo $((1 +\n2))") == [string("echo"),
+                                            arith("1 +\n2")];
+    assert tokenize("echo $((1 + 2)") == [string("echo"),
+                                          error("Missing ).", 5u)];
+    assert tokenize(token_to_string(arith("1+2"))) == [arith("1+2")];
+}
+
+#[test]
+fn test_tilde() {
+    assert tokenize("cd ~") == [string("cd"), tilde("", "")];
+    assert tokenize("ls ~/docs") == [string("ls"), tilde("", "/docs")];
+    assert tokenize("ls ~alice/src") == [string("ls"),
+                                         tilde("alice", "/src")];
+    assert tokenize("ls a~b '~' \"~/x\" \\~ ~x'y'")
+        == [string("ls"), string("a~b"), string("~"), string("~/x"),
+            string("~"), string("~xy")];
+    assert tokenize("cd ~/$d;") == [string("cd"),
+                                    word([tilde("", "/"), variable("d")]),
+                                    sequence];
+    assert tokenize("ls ~/'my docs'") == [string("ls"),
+                                          tilde("", "/my docs")];
+    for t in [tilde("", ""), tilde("bob", "/"), tilde("", "/my docs"),
+              word([tilde("", "/"), variable("d")])] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+}
+
+#[test]
+fn test_glob() {
+    assert tokenize("ls *.txt") == [string("ls"), glob("*.txt")];
+    assert tokenize("ls \"*.txt\" '*'.txt \\*.txt")
+        == [string("ls"), string("*.txt"), string("*.txt"), string("*.txt")];
+    assert tokenize("ls file[1-3] a?c") == [string("ls"), glob("file[1-3]"),
+                                            glob("a?c")];
+    assert tokenize("ls a[ b[c d]") == [string("ls"), string("a["),
+                                        string("b[c"), string("d]")];
+    assert tokenize("ls []]x [!]]") == [string("ls"), glob("[]]x"),
+                                        glob("[!]]")];
+    assert tokenize("ls \"a*\"*") == [string("ls"), glob("a\\**")];
+    assert tokenize("ls $d/*.c") == [string("ls"),
+                                     word([variable("d"), glob("/*.c")])];
+    assert word_text(glob("a\\**")) == "a**";
+    for t in [glob("*.txt"), glob("a\\**"), glob("my file*"),
+              glob("[!a]?"), word([variable("d"), glob("/*.c")])] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+}
+
+#[test]
+fn test_brace_expand() {
+    assert tokenize("touch file{1,2}.txt")
+        == [string("touch"),
+            brace_expand(@string("file"), brace_list(["1", "2"]),
+                         @string(".txt"))];
+    assert tokenize("echo {1..3} {a..c}")
+        == [string("echo"),
+            brace_expand(@string(""), brace_range("1", "3"), @string("")),
+            brace_expand(@string(""), brace_range("a", "c"), @string(""))];
+    assert tokenize("echo a{b,c{d,e}}")
+        == [string("echo"),
+            brace_expand(@string("a"), brace_list(["b", "c{d,e}"]),
+                         @string(""))];
+    assert tokenize("echo {a,b}{1,2}")
+        == [string("echo"),
+            brace_expand(@string(""), brace_list(["a", "b"]),
+                         @brace_expand(@string(""), brace_list(["1", "2"]),
+                                       @string("")))];
+    assert tokenize("echo {x} {} {a...} {1..b} '{a,b}' \\{a,b} {a,b")
+        == [string("echo"), string("{x}"), string("{}"), string("{a...}"),
+            string("{1..b}"), string("{a,b}"), string("{a,b}"),
+            string("{a,b")];
+    assert tokenize("echo {'a,b'} {\"}\",x}")
+        == [string("echo"), string("{a,b}"),
+            brace_expand(@string(""), brace_list(["\"}\"", "x"]),
+                         @string(""))];
+    assert tokenize("echo ${x} { a,b }") == [string("echo"), variable("x"),
+                                             string("{"), string("a,b"),
+                                             string("}")];
+    for t in [brace_expand(@string("file"), brace_list(["1", "2"]),
+                           @string(".txt")),
+              brace_expand(@string("a b"), brace_range("-1", "10"),
+                           @variable("x"))] {
+        assert tokenize(token_to_string(t)) == [t];
+    }
+}
+
+#[test]
+fn test_double_quote_escapes() {
+    assert tokenize("echo \"a\\$b\"") == [string("echo"), string("a$b")];
+    assert tokenize("echo \"a\\nb\" \"\\t\"")
+        == [string("echo"), string("a\\nb"), string("\\t")];
+    assert tokenize("echo \"\\`\" \"\\\\\" \"\\\"\"")
+        == [string("echo"), string("`"), string("\\"), string("\"")];
+    assert tokenize("echo \"a\\\nb\"") == [string("echo"), string("ab")];
+    assert tokenize("echo \"a\\'\"") == [string("echo"), string("a\\'")];
+}
+
+#[test]
+fn test_long_input() {
+    let line = "";
+    let i = 0u;
+    while i < 5000u {
+        line += "echo \"f\u00f6o $x\" | grep b\u00e4r > out; ";
+        i += 1u;
+    }
+    let tokens = tokenize_spans(default_config(), line);
+    assert vec::len(tokens) == 5000u * 7u;
+    assert tokens[0] == (string("echo"), {start: 0u, end: 4u});
+    assert tokens[1] == (word([string("f\u00f6o "), variable("x")]),
+                         {start: 5u, end: 13u});
+    assert vec::last(tokens)
+        == (sequence, {start: str::char_len(line) - 2u,
+                       end: str::char_len(line) - 1u});
+}
+
+#[test]
+fn test_long_quoted_string() {
+    let blob = "";
+    let i = 0u;
+    while i < 10000u {
+        blob += "ab c\u00e9*";
+        i += 1u;
+    }
+    assert tokenize("echo \"" + blob + "\"") == [string("echo"),
+                                                 string(blob)];
+    assert tokenize("echo '" + blob + "'x") == [string("echo"),
+                                                string(blob + "x")];
+    assert tokenize("echo x\"" + blob + "\"*")
+        == [string("echo"), glob("x" + escape_glob(blob) + "*")];
+}
+
+#[test]
+fn test_detokenize() {
+    assert detokenize([]) == "";
+    assert detokenize([string("echo"), string("a b"), redirect_error_to_output,
+                       pipe, string("wc"), and, string("ls")])
+        == "echo 'a b' 2>&1 | wc && ls";
+    assert detokenize([string("cat"),
+                       here_doc({delimiter: "EOF", body: "x\n",
+                                 quoted: false, strip_tabs: false}),
+                       sequence, string("ls")])
+        == "cat <<EOF ; ls\nx\nEOF";
+    let lines = ["ls -l | grep foo > out", "foo 2>&1", "a && b || (c; d) &",
+                 "echo \"a\\\"b\" 'it''s'", "a &>>log", "x |& y",
+                 "exec 3>&- 4<in 5>&2 6>f 7<g", "! ls; ! a | b",
+                 "cat <<EOF; cat <<'END' | wc\nhi $x\nEOF\n\nEND\nls",
+                 "grep foo <<< \"$bar baz\"", "echo ${x:-a} $(date) $((1+2))",
+                 "echo ~ ~/d ~u/'a b' \\~", "ls *.txt 'a*'* [!a]?",
+                 "touch file{1,2}.txt {a..c}", "a$B_1.c \"$x\"y",
+                 "a\nb\n\nc", "echo h\u00e9 'x\u00a0y'",
+                 "cat <<$END\n$x\n$END", "cat <<'$E F'\n$x\n$E F"];
+    for line in lines {
+        let ts = tokenize(line);
+        assert tokenize(detokenize(ts)) == ts;
+    }
+}
+
+#[test]
+fn test_token_to_string_variants() {
+    let doc = {delimiter: "EOF", body: "x\n", quoted: false,
+               strip_tabs: false};
+    let all = [string("a"), variable("a"),
+               parameter("a", use_default, @string("b")),
+               command_subst("a"), arith("1"), tilde("a", "/b"), glob("*"),
+               brace_expand(@string("a"), brace_list(["b", "c"]),
+                            @string("")),
+               word([string("a"), variable("b")]), pipe, pipe_both,
+               redirect_output(@string("a")),
+               redirect_output_append(@string("a")),
+               redirect_error(@string("a")),
+               redirect_error_append(@string("a")), redirect_error_to_output,
+               redirect_both(@string("a")), redirect_both_append(@string("a")),
+               redirect_input(@string("a")), here_doc(doc),
+               here_string(@string("a")), redirect_fd(3u, @string("a")),
+               redirect_fd_input(3u, @string("a")), fd_here_doc(3u, doc),
+               fd_here_string(3u, @string("a")), dup_fd(3u, 2u),
+               close_fd(3u), and, or, background, negate, sequence,
+               open_subshell, close_subshell, continuation(""),
+               error("oops", 0u)];
+    let seen: [str] = [];
+    for t in all {
+        let s = token_to_string(t);
+        assert !str::is_empty(s);
+        assert !vec::contains(seen, s);
+        seen += [s];
+    }
+    assert token_to_string(error("oops", 3u)) == "Error: 'oops'";
 }